@@ -0,0 +1,230 @@
+use std::collections::HashSet;
+
+use crate::lex_parse_basic::{BASIC, Node};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+  Error,
+  // the program can't run as written, so it isn't started
+  Warning,
+  // probably a mistake, but the program can still run
+}
+
+// walks the program looking for errors that would otherwise only show up once execution
+// reaches them
+struct Checker<'a> {
+  basic: &'a BASIC,
+  line_no: usize,
+  in_type: bool,
+  // field names aren't variables
+  assigned: HashSet<&'a str>,
+  reads: Vec<(usize, &'a str)>,
+  findings: Vec<(usize, String)>,
+}
+
+impl<'a> Checker<'a> {
+  fn finding(&mut self, message: String) {
+    self.findings.push((self.line_no, message));
+  }
+
+  fn target(&mut self, target: &'a Node, cmd: &str) {
+    match target {
+      Node::Integer(line_no) => {
+        if !self.basic.line_pcs.contains_key(&(*line_no as usize)) {
+          self.finding(format!("{}: Could not find line {}", cmd, line_no));
+        }
+      }
+      _ => self.node(target),
+    }
+  }
+
+  fn arity(&mut self, name: &str, min: usize, max: usize, got: usize) {
+    if (min..=max).contains(&got) {
+      return;
+    }
+
+    let expected = if min == max {
+      format!("{}", min)
+    } else if max == usize::MAX {
+      format!("at least {}", min)
+    } else {
+      format!("{} to {}", min, max)
+    };
+    self.finding(format!("{}: Expected {} arguments, got {}", name, expected, got));
+  }
+
+  fn node(&mut self, node: &'a Node) {
+    match node {
+      Node::For { name, start, end, step } => {
+        self.assigned.insert(name);
+        self.node(start);
+        self.node(end);
+        self.node(step);
+      }
+      Node::If { cond, then, else_ } => {
+        self.node(cond);
+        for node in then.iter().chain(else_) {
+          self.node(node);
+        }
+      }
+      Node::BlockIf { cond } | Node::ElseIf { cond } => {
+        self.node(cond);
+      }
+      Node::Def { params, .. } => {
+        self.assigned.extend(params.iter().map(|it| it.as_str()));
+      }
+      Node::Return(value) => {
+        self.node(value);
+      }
+      Node::Local(names) => {
+        self.assigned.extend(names.iter().map(|it| it.as_str()));
+      }
+      Node::Call { name, args } => {
+        match self.basic.function_names.get(name) {
+          _ if self.basic.types.contains_key(name) => {
+            self.arity(name, 0, self.basic.types[name].fields.len(), args.len());
+          }
+          Some(&idx) => {
+            let params = self.basic.functions[idx].params.len();
+            self.arity(name, params, params, args.len());
+          }
+          None => self.finding(format!("Unknown function {}", name)),
+        }
+
+        for arg in args {
+          self.node(arg);
+        }
+      }
+      Node::Read(targets) => {
+        for target in targets {
+          match target {
+            Node::VarGet(name) => {
+              self.assigned.insert(name);
+            }
+            _ => self.node(target),
+          }
+        }
+      }
+      Node::Input { prompt, target } => {
+        self.node(prompt);
+        match &**target {
+          Node::VarGet(name) => {
+            self.assigned.insert(name);
+          }
+          target => self.node(target),
+        }
+      }
+      Node::Restore(target) if **target != Node::Nil => self.target(target, "Restore"),
+      Node::OnError(target) if **target != Node::Integer(0) => self.target(target, "On error"),
+      Node::LabelRef(name) if !self.basic.label_lines.contains_key(name) => {
+        self.finding(format!("Unknown label @{}", name));
+      }
+      Node::Assign { name, value } => {
+        self.assigned.insert(name);
+        self.node(value);
+      }
+      Node::BinOp { left, right, .. } => {
+        self.node(left);
+        self.node(right);
+      }
+      Node::UnOp { right, .. } => {
+        self.node(right);
+      }
+      Node::BuiltinCommand { name, args } => {
+        if let Some(&(min, max)) = self.basic.options.builtin_arities.get(name.as_str()) {
+          self.arity(name, min, max, args.len());
+        }
+
+        match (name.as_str(), args.as_slice()) {
+          ("goto", [target]) => self.target(target, "Goto"),
+          ("gosub", [target]) => self.target(target, "Gosub"),
+          _ => {
+            for arg in args {
+              self.node(arg);
+            }
+          }
+        }
+      }
+      Node::Type(_) => {
+        self.in_type = true;
+      }
+      Node::EndType => {
+        self.in_type = false;
+      }
+      Node::VarGet(name) if !self.in_type => {
+        self.reads.push((self.line_no, name));
+      }
+      Node::Array(elements) => {
+        for element in elements {
+          self.node(element);
+        }
+      }
+      Node::Map(entries) => {
+        for (key, value) in entries {
+          self.node(key);
+          self.node(value);
+        }
+      }
+      Node::EmptyArray(sizes) => {
+        for size in sizes {
+          self.node(size);
+        }
+      }
+      Node::IndexGet { target, index } => {
+        self.node(target);
+        self.node(index);
+      }
+      Node::IndexSet { name, indices, value } => {
+        // the array itself has to exist already
+        self.reads.push((self.line_no, name));
+        for index in indices {
+          self.node(index);
+        }
+        self.node(value);
+      }
+      _ => {}
+    }
+  }
+}
+
+impl BASIC {
+  // every problem that can be found without running the program, ordered by line
+  pub fn check(&self) -> Vec<(Severity, String)> {
+    let mut checker = Checker {
+      basic: self,
+      line_no: 0,
+      in_type: false,
+      // set by the error handler
+      assigned: HashSet::from(["err$", "erl"]),
+      reads: vec![],
+      findings: vec![],
+    };
+
+    for line in &self.program {
+      checker.line_no = line.line_no;
+      for node in &line.nodes {
+        checker.node(node);
+      }
+    }
+
+    // block structure is already worked out by the compiler
+    let mut findings = checker.findings.iter().chain(self.mismatches.iter())
+      .map(|(line_no, message)| (*line_no, Severity::Error, message.clone()))
+      .collect::<Vec<_>>();
+
+    let mut reported = HashSet::new();
+    for &(line_no, name) in &checker.reads {
+      if !checker.assigned.contains(name) && reported.insert((line_no, name)) {
+        findings.push((line_no, Severity::Warning, format!("Variable {} is never assigned", name)));
+      }
+    }
+
+    findings.sort_by_key(|it| it.0);
+    findings.into_iter()
+      .map(|(line_no, severity, message)| match severity {
+        Severity::Error => (severity, format!("Line {}: {}", line_no, message)),
+        Severity::Warning => (severity, format!("Line {}: Warning: {}", line_no, message)),
+      })
+      .collect()
+  }
+}
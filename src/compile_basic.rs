@@ -0,0 +1,971 @@
+use std::collections::{BTreeMap, HashMap};
+use std::rc::Rc;
+
+use crate::koneko_error::{ErrorKind, KonekoError};
+use crate::lex_parse_basic::{BASIC, Line, Node, ParseOptions, RecordType, Token, Value};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Op {
+  Stmt(usize, usize),
+  // start of statement n on program[line]
+  Push(Value),
+  Pop,
+  Load(usize),
+  // push var
+  Store(usize),
+  // var = top, keeping top on the stack
+  IndexGet(usize, usize),
+  // pop n indices, push var[index1]...[indexN]
+  IndexSet(usize, usize),
+  // pop value, pop n indices, var[index1]...[indexN] = value
+  Index,
+  // pop index, pop array, push array[index]
+  MakeArray(usize),
+  // pop n values into an array
+  MakeMap(usize),
+  // pop n keys and values into a map
+  MakeRecord(Rc<RecordType>, usize),
+  // pop n values into the first n fields, leaving the rest nil
+  EmptyArray(usize),
+  // pop n sizes, push [size1, ... sizeN]
+  BinOp(Token),
+  UnOp(Token),
+  Builtin(&'static str, usize),
+  // pop n arguments, push the result
+  BuiltinInPlace(&'static str, usize, usize, usize),
+  // pop n arguments after the first, pop m indices, change var[index1]...[indexM] in place
+  Call(usize, usize),
+  // pop n arguments, call functions[idx]
+  Return,
+  EndDef,
+  Local(Vec<usize>),
+  Jump(usize),
+  JumpIfFalse(usize),
+  For(usize),
+  // pop step, end, start
+  Next(usize),
+  While {
+    cond: usize,
+    exit: usize,
+  },
+  // pop condition; cond is where loop jumps back to, exit is past the matching loop
+  Loop,
+  Goto,
+  // pop line number
+  Gosub(usize),
+  GosubLine,
+  // pop line number
+  Ret,
+  Read,
+  // push the next data value
+  Restore,
+  // rewind to the first data value
+  RestoreLine,
+  // pop line number, rewind to the first data value at or after it
+  Input(bool),
+  // pop prompt, push the line typed in, suspending the program until Return is pressed. if
+  // numeric, a line that reads as a number is pushed as one
+  OnError(Option<usize>),
+  OnErrorLine,
+  // pop line number
+  Resume(bool),
+  End,
+  // stop the program, abandoning any calls, gosubs and loops in progress
+  EndOfProgram,
+  // past the last line, which a function without an end def runs into
+  Halt,
+  // end of an immediate mode chunk
+  Error(KonekoError),
+  Unterminated(String),
+  // a block missing its end fails from the last statement, as if the search ran off the program
+}
+
+#[derive(Debug, Clone)]
+pub struct Function {
+  pub name: String,
+  pub params: Vec<usize>,
+  pub body: usize,
+}
+
+// variable names resolved to slots as the program is compiled. slots are never handed out
+// again, so values set from the prompt keep their place when the program changes
+#[derive(Debug, Clone, Default)]
+pub struct Slots {
+  slots: HashMap<String, usize>,
+  names: Vec<String>,
+}
+
+impl Slots {
+  pub fn resolve(&mut self, name: &str) -> usize {
+    if let Some(&slot) = self.slots.get(name) {
+      return slot;
+    }
+
+    self.slots.insert(name.to_string(), self.names.len());
+    self.names.push(name.to_string());
+    self.names.len() - 1
+  }
+
+  pub fn name(&self, slot: usize) -> &str {
+    &self.names[slot]
+  }
+}
+
+// builtins that change the array or map passed as their first argument
+const IN_PLACE_BUILTINS: [&str; 7] = ["push", "pop", "insert", "remove", "reverse", "sort", "delete"];
+
+// jump targets that are only known once the whole program has been emitted
+enum Target {
+  Line(usize, &'static str),
+  Label(String, &'static str),
+  Unterminated(String),
+}
+
+struct IfBlock {
+  line_no: usize,
+  next_branch: Option<usize>,
+  ends: Vec<usize>,
+}
+
+// a while inside a single line if isn't part of the block structure, it exits past the first
+// loop that closes a block at or above its depth
+struct LooseWhile {
+  line_no: usize,
+  at: usize,
+  depth: usize,
+}
+
+struct Compiler<'a> {
+  options: &'a ParseOptions,
+  base: usize,
+  code: Vec<Op>,
+  functions: Vec<Function>,
+  function_names: HashMap<String, usize>,
+  types: HashMap<String, Rc<RecordType>>,
+  label_lines: HashMap<String, usize>,
+  line_pcs: HashMap<usize, usize>,
+  label_pcs: HashMap<String, usize>,
+  slots: Slots,
+  fixups: Vec<(usize, Target)>,
+  line_indices: HashMap<usize, usize>,
+  if_blocks: Vec<IfBlock>,
+  whiles: Vec<(usize, usize)>,
+  loose_whiles: Vec<LooseWhile>,
+  fors: Vec<usize>,
+  defs: Vec<(usize, usize)>,
+  def_count: usize,
+  type_line: Option<usize>,
+  // the line of the type whose fields are being declared
+  immediate: bool,
+  line_no: usize,
+  conditional: usize,
+  // statements inside a single line if, which don't open or close blocks
+  mismatches: Vec<(usize, String)>,
+}
+
+impl<'a> Compiler<'a> {
+  fn pc(&self) -> usize {
+    self.base + self.code.len()
+  }
+
+  fn emit(&mut self, op: Op) -> usize {
+    self.code.push(op);
+    self.code.len() - 1
+  }
+
+  fn patch(&mut self, at: usize, target: usize) {
+    match &mut self.code[at] {
+      Op::Jump(pc) | Op::JumpIfFalse(pc) | Op::Gosub(pc) | Op::While { exit: pc, .. } => *pc = target,
+      Op::OnError(pc) => *pc = Some(target),
+      op => panic!("Cannot patch jump target of {:?}", op),
+    }
+  }
+
+  fn patch_here(&mut self, at: usize) {
+    let pc = self.pc();
+    self.patch(at, pc);
+  }
+
+  fn stub(&mut self, at: usize, message: &str) {
+    self.fixups.push((at, Target::Unterminated(message.to_string())));
+  }
+
+  fn mismatch(&mut self, line_no: usize, message: &str) {
+    self.mismatches.push((line_no, message.to_string()));
+  }
+
+  // emits the error stubs and resolves every outstanding jump
+  fn finish(&mut self) {
+    for block in std::mem::take(&mut self.if_blocks) {
+      self.mismatch(block.line_no, "If has no matching end if");
+      for at in block.next_branch.into_iter().chain(block.ends) {
+        self.stub(at, "If: Could not find matching end if");
+      }
+    }
+
+    let whiles = std::mem::take(&mut self.whiles).into_iter()
+      .chain(std::mem::take(&mut self.loose_whiles).into_iter().map(|it| (it.at, it.line_no)));
+    for (at, line_no) in whiles {
+      self.mismatch(line_no, "While has no matching loop");
+      self.stub(at, "Unexpected end of program!");
+    }
+
+    for line_no in std::mem::take(&mut self.fors) {
+      self.mismatch(line_no, "For has no matching next");
+    }
+
+    for (at, line_no) in std::mem::take(&mut self.defs) {
+      self.mismatch(line_no, "Def has no matching end def");
+      self.stub(at, "Def: Could not find matching end def");
+    }
+
+    if let Some(line_no) = self.type_line.take() {
+      self.mismatch(line_no, "Type has no matching end type");
+    }
+
+    let mut stubs = HashMap::<String, usize>::new();
+    for (at, target) in std::mem::take(&mut self.fixups) {
+      let message = match target {
+        Target::Line(line_no, cmd) => match self.line_pcs.get(&line_no) {
+          Some(&pc) => {
+            self.patch(at, pc);
+            continue;
+          }
+          None => format!("{}: Could not find line {}", cmd, line_no),
+        },
+        Target::Label(name, cmd) => match self.label_pcs.get(&name) {
+          Some(&pc) => {
+            self.patch(at, pc);
+            continue;
+          }
+          None => format!("{}: Unknown label @{}", cmd, name),
+        },
+        Target::Unterminated(message) => {
+          let pc = match stubs.get(&message) {
+            Some(&pc) => pc,
+            None => {
+              let pc = self.pc();
+              self.emit(Op::Unterminated(message.clone()));
+              stubs.insert(message, pc);
+              pc
+            }
+          };
+          self.patch(at, pc);
+          continue;
+        }
+      };
+
+      // a missing goto/gosub target fails in place, before anything is pushed
+      self.code[at] = Op::Error(KonekoError::runtime(ErrorKind::UndefinedTarget, message));
+    }
+  }
+
+  fn builtin_name(&self, name: &str) -> Option<&'static str> {
+    self.options.builtin_commands.iter()
+      .chain(self.options.builtin_vars.iter())
+      .find(|it| **it == name)
+      .copied()
+  }
+
+  fn expect_n_args(args: &[Node], n: usize) -> Option<KonekoError> {
+    if args.len() != n {
+      return Some(KonekoError::runtime(ErrorKind::ArgumentCount, format!("Expected {} arguments, got {}", n, args.len())));
+    }
+    None
+  }
+
+  // a variable, or an element of one, as the variable's name and the indices leading to the element
+  fn place(node: &Node) -> Option<(&str, Vec<&Node>)> {
+    match node {
+      Node::VarGet(name) => Some((name, vec![])),
+      Node::IndexGet { target, index } => {
+        let (name, mut indices) = Self::place(target)?;
+        indices.push(index);
+        Some((name, indices))
+      }
+      _ => None,
+    }
+  }
+
+  // emits a jump to a goto/gosub style target, or None if it has to be looked up at runtime
+  fn jump(&mut self, op: Op, target: &Node, cmd: &'static str) -> Option<usize> {
+    let target = match target {
+      Node::Integer(line_no) => Target::Line(*line_no as usize, cmd),
+      Node::LabelRef(name) => Target::Label(name.clone(), cmd),
+      _ => return None,
+    };
+
+    let at = self.emit(op);
+    self.fixups.push((at, target));
+    Some(at)
+  }
+
+  // a single line if's branch used as an expression, giving the value of its last statement
+  fn branch(&mut self, nodes: &[Node]) {
+    match nodes.split_last() {
+      Some((last, nodes)) => {
+        for node in nodes {
+          self.stmt(node);
+        }
+        self.expr(last);
+      }
+      None => self.expr(&Node::Nil),
+    }
+  }
+
+  fn stmt(&mut self, node: &Node) {
+    // fields were collected before emitting, so a type's body has nothing to run
+    if self.type_line.is_some() && *node != Node::EndType {
+      if !matches!(node, Node::VarGet(_) | Node::Comment(_)) {
+        self.mismatch(self.line_no, "Type can only hold field names");
+        self.emit(Op::Error(KonekoError::runtime(ErrorKind::Syntax, "Type: Expected field name".to_string())));
+      }
+      return;
+    }
+
+    match node {
+      Node::For { name, start, end, step } => {
+        self.expr(start);
+        self.expr(end);
+        self.expr(step);
+        let slot = self.slots.resolve(name);
+        self.emit(Op::For(slot));
+        if self.conditional == 0 {
+          self.fors.push(self.line_no);
+        }
+      }
+      Node::If { cond, then, else_ } => {
+        self.expr(cond);
+        let to_else = self.emit(Op::JumpIfFalse(0));
+        self.conditional += 1;
+        for node in then {
+          self.stmt(node);
+        }
+        let to_end = self.emit(Op::Jump(0));
+        self.patch_here(to_else);
+        for node in else_ {
+          self.stmt(node);
+        }
+        self.conditional -= 1;
+        self.patch_here(to_end);
+      }
+      Node::BlockIf { cond } => {
+        self.expr(cond);
+        let next_branch = self.emit(Op::JumpIfFalse(0));
+        self.if_blocks.push(IfBlock { line_no: self.line_no, next_branch: Some(next_branch), ends: vec![] });
+      }
+      Node::ElseIf { cond } => {
+        // falling out of the taken branch skips to end if
+        let to_end = self.emit(Op::Jump(0));
+        let Some(block) = self.if_blocks.last_mut() else {
+          self.mismatch(self.line_no, "Elseif has no matching if");
+          self.stub(to_end, "If: Could not find matching end if");
+          return;
+        };
+        block.ends.push(to_end);
+
+        if let Some(next_branch) = block.next_branch.take() {
+          self.patch_here(next_branch);
+        }
+        self.expr(cond);
+        let next_branch = self.emit(Op::JumpIfFalse(0));
+        self.if_blocks.last_mut().unwrap().next_branch = Some(next_branch);
+      }
+      Node::Else => {
+        let to_end = self.emit(Op::Jump(0));
+        let Some(block) = self.if_blocks.last_mut() else {
+          self.mismatch(self.line_no, "Else has no matching if");
+          self.stub(to_end, "If: Could not find matching end if");
+          return;
+        };
+        block.ends.push(to_end);
+
+        if let Some(next_branch) = block.next_branch.take() {
+          self.patch_here(next_branch);
+        }
+      }
+      Node::EndIf => {
+        let Some(block) = self.if_blocks.pop() else {
+          self.mismatch(self.line_no, "End if has no matching if");
+          return;
+        };
+        for at in block.next_branch.into_iter().chain(block.ends) {
+          self.patch_here(at);
+        }
+      }
+      Node::Def { .. } | Node::EndDef if self.conditional > 0 => {
+        // compile only collects functions at the top level of a line, so one here has no body to fill in
+        self.mismatch(self.line_no, "Def can't be inside a single line if");
+        let message = "Def: Cannot define a function inside a single line if".to_string();
+        self.emit(Op::Error(KonekoError::runtime(ErrorKind::Misplaced, message)));
+      }
+      Node::Def { .. } => {
+        // function bodies only run when called
+        let skip = self.emit(Op::Jump(0));
+        self.defs.push((skip, self.line_no));
+
+        // functions are collected before emitting, so only their bodies are filled in here
+        if !self.immediate {
+          let pc = self.pc();
+          if let Some(function) = self.functions.get_mut(self.def_count) {
+            function.body = pc;
+            self.def_count += 1;
+          }
+        }
+      }
+      Node::EndDef => {
+        self.emit(Op::EndDef);
+        if self.defs.is_empty() {
+          self.mismatch(self.line_no, "End def has no matching def");
+        }
+        for (at, _) in std::mem::take(&mut self.defs) {
+          self.patch_here(at);
+        }
+      }
+      Node::Type(_) => {
+        self.type_line = Some(self.line_no);
+      }
+      Node::EndType => {
+        if self.type_line.take().is_none() {
+          self.mismatch(self.line_no, "End type has no matching type");
+        }
+      }
+      Node::Return(value) => {
+        self.expr(value);
+        self.emit(Op::Return);
+      }
+      Node::Local(names) => {
+        let slots = names.iter().map(|name| self.slots.resolve(name)).collect();
+        self.emit(Op::Local(slots));
+      }
+      Node::Label(_) | Node::Comment(_) | Node::Data(_) | Node::Nil => {}
+      Node::Read(targets) => {
+        for target in targets {
+          let Some((name, indices)) = Self::place(target) else {
+            let message = format!("Read: Expected variable, got {:?}", target);
+            self.emit(Op::Error(KonekoError::runtime(ErrorKind::Syntax, message)));
+            continue;
+          };
+
+          let slot = self.slots.resolve(name);
+          if indices.is_empty() {
+            self.emit(Op::Read);
+            self.emit(Op::Store(slot));
+          } else {
+            for index in &indices {
+              self.expr(index);
+            }
+            self.emit(Op::Read);
+            self.emit(Op::IndexSet(slot, indices.len()));
+          }
+          self.emit(Op::Pop);
+        }
+      }
+      Node::Input { prompt, target } => {
+        let Some((name, indices)) = Self::place(target) else {
+          let message = format!("Input: Expected variable, got {:?}", target);
+          self.emit(Op::Error(KonekoError::runtime(ErrorKind::Syntax, message)));
+          return;
+        };
+
+        for index in &indices {
+          self.expr(index);
+        }
+        self.expr(prompt);
+        // like classic BASIC, only variables without a $ suffix take numbers
+        self.emit(Op::Input(!name.ends_with('$')));
+        let slot = self.slots.resolve(name);
+        if indices.is_empty() {
+          self.emit(Op::Store(slot));
+        } else {
+          self.emit(Op::IndexSet(slot, indices.len()));
+        }
+        self.emit(Op::Pop);
+      }
+      Node::Restore(target) => {
+        if **target == Node::Nil {
+          self.emit(Op::Restore);
+        } else {
+          self.expr(target);
+          self.emit(Op::RestoreLine);
+        }
+      }
+      Node::OnError(target) => {
+        if let Node::Integer(0) = **target {
+          self.emit(Op::OnError(None));
+        } else if self.jump(Op::OnError(None), target, "On error").is_none() {
+          self.expr(target);
+          self.emit(Op::OnErrorLine);
+        }
+      }
+      Node::Resume(next) => {
+        self.emit(Op::Resume(*next));
+      }
+      Node::End => {
+        self.emit(Op::End);
+      }
+      Node::BuiltinCommand { name, args } => {
+        let error = match name.as_str() {
+          "next" => match Self::expect_n_args(args, 1) {
+            Some(error) => Some(error),
+            None => match &args[0] {
+              Node::VarGet(name) => {
+                let slot = self.slots.resolve(name);
+                self.emit(Op::Next(slot));
+                // a conditional next continues the loop early, it doesn't close it
+                if self.conditional == 0 && self.fors.pop().is_none() {
+                  self.mismatch(self.line_no, "Next has no matching for");
+                }
+                None
+              }
+              arg => Some(KonekoError::runtime(ErrorKind::Syntax, format!("Expected variable name, got {:?}", arg))),
+            },
+          },
+          "while" => Self::expect_n_args(args, 1).or_else(|| {
+            let cond = self.pc();
+            self.expr(&args[0]);
+            let at = self.emit(Op::While { cond, exit: 0 });
+            if self.conditional == 0 {
+              self.whiles.push((at, self.line_no));
+            } else {
+              self.loose_whiles.push(LooseWhile { line_no: self.line_no, at, depth: self.whiles.len() });
+            }
+            None
+          }),
+          "loop" => Self::expect_n_args(args, 0).or_else(|| {
+            self.emit(Op::Loop);
+            if self.conditional > 0 {
+              return None;
+            }
+
+            let depth = self.whiles.len();
+            let mut matched = false;
+            if let Some((at, _)) = self.whiles.pop() {
+              self.patch_here(at);
+              matched = true;
+            }
+            for it in std::mem::take(&mut self.loose_whiles) {
+              if depth <= it.depth {
+                self.patch_here(it.at);
+                matched = true;
+              } else {
+                self.loose_whiles.push(it);
+              }
+            }
+
+            if !matched {
+              self.mismatch(self.line_no, "Loop has no matching while");
+            }
+            None
+          }),
+          "goto" => Self::expect_n_args(args, 1).or_else(|| {
+            if self.jump(Op::Jump(0), &args[0], "Goto").is_none() {
+              self.expr(&args[0]);
+              self.emit(Op::Goto);
+            }
+            None
+          }),
+          "gosub" => Self::expect_n_args(args, 1).or_else(|| {
+            if self.jump(Op::Gosub(0), &args[0], "Gosub").is_none() {
+              self.expr(&args[0]);
+              self.emit(Op::GosubLine);
+            }
+            None
+          }),
+          "ret" => Self::expect_n_args(args, 0).or_else(|| {
+            self.emit(Op::Ret);
+            None
+          }),
+          "end" => Self::expect_n_args(args, 0).or_else(|| {
+            self.emit(Op::End);
+            None
+          }),
+          _ => {
+            self.expr(node);
+            self.emit(Op::Pop);
+            None
+          }
+        };
+
+        if let Some(error) = error {
+          self.emit(Op::Error(error));
+        }
+      }
+      _ => {
+        self.expr(node);
+        self.emit(Op::Pop);
+      }
+    }
+  }
+
+  fn expr(&mut self, node: &Node) {
+    match node {
+      Node::Integer(num) => {
+        self.emit(Op::Push(Value::Integer(*num)));
+      }
+      Node::Float(num) => {
+        self.emit(Op::Push(Value::Float(*num)));
+      }
+      Node::String(string) => {
+        self.emit(Op::Push(Value::String(string.clone())));
+      }
+      Node::Nil => {
+        self.emit(Op::Push(Value::Nil));
+      }
+      Node::VarGet(name) => {
+        let slot = self.slots.resolve(name);
+        self.emit(Op::Load(slot));
+      }
+      Node::Assign { name, value } => {
+        self.expr(value);
+        let slot = self.slots.resolve(name);
+        self.emit(Op::Store(slot));
+      }
+      Node::If { cond, then, else_ } => {
+        self.expr(cond);
+        let to_else = self.emit(Op::JumpIfFalse(0));
+        self.conditional += 1;
+        self.branch(then);
+        let to_end = self.emit(Op::Jump(0));
+        self.patch_here(to_else);
+        self.branch(else_);
+        self.conditional -= 1;
+        self.patch_here(to_end);
+      }
+      Node::BinOp { op: Token::Ampersand, left, right } => {
+        // the right side only runs when the left doesn't decide the result
+        self.expr(left);
+        let left_false = self.emit(Op::JumpIfFalse(0));
+        self.expr(right);
+        let right_false = self.emit(Op::JumpIfFalse(0));
+        self.emit(Op::Push(Value::Integer(1)));
+        let to_end = self.emit(Op::Jump(0));
+        self.patch_here(left_false);
+        self.patch_here(right_false);
+        self.emit(Op::Push(Value::Integer(0)));
+        self.patch_here(to_end);
+      }
+      Node::BinOp { op: Token::Pipe, left, right } => {
+        self.expr(left);
+        let to_right = self.emit(Op::JumpIfFalse(0));
+        self.emit(Op::Push(Value::Integer(1)));
+        let left_end = self.emit(Op::Jump(0));
+        self.patch_here(to_right);
+        self.expr(right);
+        let right_false = self.emit(Op::JumpIfFalse(0));
+        self.emit(Op::Push(Value::Integer(1)));
+        let right_end = self.emit(Op::Jump(0));
+        self.patch_here(right_false);
+        self.emit(Op::Push(Value::Integer(0)));
+        self.patch_here(left_end);
+        self.patch_here(right_end);
+      }
+      Node::BinOp { op, left, right } => {
+        self.expr(left);
+        self.expr(right);
+        self.emit(Op::BinOp(op.clone()));
+      }
+      Node::UnOp { op, right } => {
+        self.expr(right);
+        self.emit(Op::UnOp(op.clone()));
+      }
+      Node::BuiltinCommand { name, args } if IN_PLACE_BUILTINS.contains(&name.as_str()) => {
+        // the first argument is the array or map to change, so it's passed as a variable instead of a copy
+        let place = args.first().and_then(Self::place);
+        let (Some(name), Some((var, indices))) = (self.builtin_name(name), place) else {
+          let message = format!("{}: Expected a variable as the first argument", name);
+          self.emit(Op::Error(KonekoError::runtime(ErrorKind::InvalidArgument, message)));
+          return;
+        };
+
+        for index in &indices {
+          self.expr(index);
+        }
+        for arg in &args[1..] {
+          self.expr(arg);
+        }
+        let slot = self.slots.resolve(var);
+        self.emit(Op::BuiltinInPlace(name, slot, indices.len(), args.len() - 1));
+      }
+      Node::BuiltinCommand { name, args }
+      if !matches!(name.as_str(), "next" | "while" | "loop" | "goto" | "gosub" | "ret" | "end") => {
+        for arg in args {
+          self.expr(arg);
+        }
+
+        match self.builtin_name(name) {
+          Some(name) => self.emit(Op::Builtin(name, args.len())),
+          None => self.emit(Op::Error(KonekoError::runtime(ErrorKind::UndefinedFunction, format!("Unknown builtin command {}", name)))),
+        };
+      }
+      Node::Call { name, args } => {
+        for arg in args {
+          self.expr(arg);
+        }
+
+        if let Some(record) = self.types.get(name) {
+          if args.len() > record.fields.len() {
+            let message = format!("{}: Expected at most {} arguments, got {}", name, record.fields.len(), args.len());
+            self.emit(Op::Error(KonekoError::runtime(ErrorKind::ArgumentCount, message)));
+          } else {
+            self.emit(Op::MakeRecord(record.clone(), args.len()));
+          }
+          return;
+        }
+
+        match self.function_names.get(name) {
+          Some(&idx) => self.emit(Op::Call(idx, args.len())),
+          None => self.emit(Op::Error(KonekoError::runtime(ErrorKind::UndefinedFunction, format!("Unknown function {}", name)))),
+        };
+      }
+      Node::Array(elements) => {
+        for element in elements {
+          self.expr(element);
+        }
+        self.emit(Op::MakeArray(elements.len()));
+      }
+      Node::Map(entries) => {
+        for (key, value) in entries {
+          self.expr(key);
+          self.expr(value);
+        }
+        self.emit(Op::MakeMap(entries.len()));
+      }
+      Node::EmptyArray(sizes) => {
+        for size in sizes {
+          self.expr(size);
+        }
+        self.emit(Op::EmptyArray(sizes.len()));
+      }
+      Node::IndexGet { .. } => {
+        let mut target = node;
+        let mut indices = vec![];
+        while let Node::IndexGet { target: inner, index } = target {
+          indices.push(index);
+          target = inner;
+        }
+        indices.reverse();
+
+        // a variable is indexed in place, without copying the arrays on the way down
+        if let Node::VarGet(name) = target {
+          for index in &indices {
+            self.expr(index);
+          }
+          let slot = self.slots.resolve(name);
+          self.emit(Op::IndexGet(slot, indices.len()));
+        } else {
+          self.expr(target);
+          for index in indices {
+            self.expr(index);
+            self.emit(Op::Index);
+          }
+        }
+      }
+      Node::IndexSet { name, indices, value } => {
+        for index in indices {
+          self.expr(index);
+        }
+        self.expr(value);
+        let slot = self.slots.resolve(name);
+        self.emit(Op::IndexSet(slot, indices.len()));
+      }
+      Node::LabelRef(name) => {
+        match self.label_lines.get(name) {
+          Some(&line_no) => self.emit(Op::Push(Value::Integer(line_no as i64))),
+          None => self.emit(Op::Error(KonekoError::runtime(ErrorKind::UndefinedTarget, format!("Unknown label @{}", name)))),
+        };
+      }
+      _ => {
+        // statements used as values evaluate to nil
+        self.stmt(node);
+        self.emit(Op::Push(Value::Nil));
+      }
+    }
+  }
+}
+
+impl BASIC {
+  fn compiler(options: &ParseOptions, base: usize, immediate: bool) -> Compiler<'_> {
+    Compiler {
+      options,
+      base,
+      code: vec![],
+      functions: vec![],
+      function_names: HashMap::new(),
+      types: HashMap::new(),
+      label_lines: HashMap::new(),
+      line_pcs: HashMap::new(),
+      label_pcs: HashMap::new(),
+      slots: Slots::default(),
+      fixups: vec![],
+      line_indices: HashMap::new(),
+      if_blocks: vec![],
+      whiles: vec![],
+      loose_whiles: vec![],
+      fors: vec![],
+      defs: vec![],
+      def_count: 0,
+      type_line: None,
+      immediate,
+      line_no: 0,
+      conditional: 0,
+      mismatches: vec![],
+    }
+  }
+
+  // rebuilds the bytecode and block structure for the whole program
+  pub fn compile(&mut self) {
+    let mut compiler = Self::compiler(&self.options, 0, false);
+    compiler.slots = std::mem::take(&mut self.slots);
+
+    let mut record: Option<RecordType> = None;
+    let mut data = vec![];
+    let mut data_lines = BTreeMap::new();
+    for Line { line_no, nodes, .. } in &self.program {
+      for node in nodes {
+        if let Some(record) = &mut record {
+          if let Node::VarGet(field) = node {
+            record.fields.push(field.clone());
+          }
+        }
+
+        match node {
+          Node::Def { name, params } => {
+            compiler.function_names.insert(name.clone(), compiler.functions.len());
+            let params = params.iter().map(|param| compiler.slots.resolve(param)).collect();
+            compiler.functions.push(Function { name: name.clone(), params, body: 0 });
+          }
+          Node::Type(name) => {
+            record = Some(RecordType { name: name.clone(), fields: vec![] });
+          }
+          Node::EndType => {
+            if let Some(record) = record.take() {
+              compiler.types.insert(record.name.clone(), Rc::new(record));
+            }
+          }
+          Node::Label(name) => {
+            compiler.label_lines.insert(name.clone(), *line_no);
+          }
+          Node::Data(values) => {
+            data_lines.entry(*line_no).or_insert(data.len());
+            data.extend(values.iter().cloned());
+          }
+          _ => {}
+        }
+      }
+    }
+
+    for (idx, line) in self.program.iter().enumerate() {
+      compiler.line_no = line.line_no;
+      compiler.line_indices.insert(line.line_no, idx);
+      compiler.line_pcs.insert(line.line_no, compiler.pc());
+      for (n, node) in line.nodes.iter().enumerate() {
+        if let Node::Label(name) = node {
+          compiler.label_pcs.insert(name.clone(), compiler.pc());
+        }
+        compiler.emit(Op::Stmt(idx, n));
+        compiler.stmt(node);
+      }
+    }
+
+    let program_end = compiler.emit(Op::EndOfProgram);
+    compiler.finish();
+
+    self.mismatches = compiler.mismatches;
+    self.line_indices = compiler.line_indices;
+
+    let code = compiler.code;
+    self.functions = compiler.functions;
+    self.function_names = compiler.function_names;
+    self.types = compiler.types;
+    self.line_pcs = compiler.line_pcs;
+    self.label_pcs = compiler.label_pcs;
+    self.label_lines = compiler.label_lines;
+    self.slots = compiler.slots;
+    self.data = data;
+    self.data_lines = data_lines;
+    self.program_code_len = code.len();
+    self.code = Rc::new(code);
+    self.program_end = program_end;
+  }
+
+  // appends an immediate mode line to the program's bytecode, replacing the previous one,
+  // and returns where it starts
+  pub fn compile_immediate(&mut self, nodes: &[Node]) -> usize {
+    let base = self.program_code_len;
+    let mut compiler = Self::compiler(&self.options, base, true);
+    compiler.function_names = self.function_names.clone();
+    compiler.types = self.types.clone();
+    compiler.line_pcs = self.line_pcs.clone();
+    compiler.label_pcs = self.label_pcs.clone();
+    compiler.label_lines = self.label_lines.clone();
+    compiler.slots = std::mem::take(&mut self.slots);
+
+    for (idx, node) in nodes.iter().enumerate() {
+      if idx + 1 == nodes.len() {
+        compiler.expr(node);
+      } else {
+        compiler.stmt(node);
+      }
+    }
+    compiler.emit(Op::Halt);
+    compiler.finish();
+
+    let code = compiler.code;
+    self.slots = compiler.slots;
+    let program = Rc::make_mut(&mut self.code);
+    program.truncate(base);
+    program.extend(code);
+    base
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::koneko::{Koneko, millis};
+  use crate::koneko_error::ErrorKind;
+  use crate::lex_parse_basic::Value;
+  use crate::palette;
+
+  // runs a program where f() counts its calls in `calls`, then returns the globals named
+  fn run_with_counter(body: &str, names: &[&str]) -> Vec<Option<Value>> {
+    let mut ko = Koneko::new(palette::sweetie_16(), "font.png");
+    let source = format!("10 calls = 0\n20 def f()\n30 calls = calls + 1\n40 return 1\n50 end def\n{}", body);
+    ko.basic.load_program(&source).expect("program should load");
+    ko.basic.reset_program_state();
+    ko.run_program(millis()).expect("program should run");
+
+    names.iter().map(|name| {
+      let slot = ko.basic.slots.resolve(name);
+      ko.basic.get_var(slot).cloned()
+    }).collect()
+  }
+
+  #[test]
+  fn and_skips_right_side_when_left_is_false() {
+    let vars = run_with_counter("60 a = 0 & f()", &["a", "calls"]);
+    assert_eq!(vars, vec![Some(Value::Integer(0)), Some(Value::Integer(0))]);
+  }
+
+  #[test]
+  fn or_skips_right_side_when_left_is_true() {
+    let vars = run_with_counter("60 a = 5 | f()", &["a", "calls"]);
+    assert_eq!(vars, vec![Some(Value::Integer(1)), Some(Value::Integer(0))]);
+  }
+
+  #[test]
+  fn right_side_runs_when_left_does_not_decide() {
+    let vars = run_with_counter("60 a = 5 & f()\n70 b = 0 | f()", &["a", "b", "calls"]);
+    assert_eq!(vars, vec![Some(Value::Integer(1)), Some(Value::Integer(1)), Some(Value::Integer(2))]);
+  }
+
+  #[test]
+  fn def_inside_single_line_if_is_misplaced() {
+    let mut ko = Koneko::new(palette::sweetie_16(), "font.png");
+    ko.basic.load_program("10 if 1 then def f()\n20 end def").expect("program should load");
+    ko.basic.reset_program_state();
+    let error = ko.run_program(millis()).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::Misplaced);
+    assert_eq!(error.line_no(), Some(10));
+  }
+}
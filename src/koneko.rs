@@ -9,7 +9,9 @@ use image::io::Reader as ImageReader;
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
 
+use crate::check_basic::Severity;
 use crate::csv;
+use crate::koneko_error::KonekoError;
 use crate::lex_parse_basic::{BASIC, ParseOptions, Token};
 use crate::palette::Sweetie16;
 
@@ -21,6 +23,8 @@ pub(crate) const BASIC_TEXT_HEIGHT: i32 = TEXT_HEIGHT - 3;
 pub(crate) const FONT_TEXTURE_SIZE: i32 = 160;
 pub(crate) static mut PROGRAM_BEGIN: u128 = 0;
 pub(crate) const COLOR_PREFIX: u8 = b'`';
+pub(crate) const PLACEHOLDER_CHAR: u8 = b'?';
+pub(crate) const MAX_FINDINGS: usize = 8;
 
 #[inline]
 pub fn millis() -> u128 {
@@ -52,6 +56,17 @@ impl Character {
   }
 }
 
+// the line a running program is waiting on with input, typed in on its last printed line
+#[derive(Debug, Clone)]
+pub struct Input {
+  pub prompt: String,
+  pub line: String,
+  pub cursor: i32,
+  pub row: usize,
+  pub done: bool,
+  // Return was pressed, the program picks the line up when it resumes
+}
+
 pub(crate) const BASIC_SCREEN: i32 = 0;
 pub(crate) const EXEC_SCREEN: i32 = 1;
 
@@ -70,7 +85,12 @@ pub struct Koneko {
   pub line_scroll: i32,
   pub prev_cursor_on: bool,
   pub error: Option<String>,
+  pub error_at: Option<(String, (usize, usize))>,
+  // the line at the prompt an error was found in and the span it points at, underlined until the
+  // line is changed
   pub ok: Option<String>,
+  pub findings: Vec<String>,
+  pub input: Option<Input>,
   pub keys_down: Vec<Keycode>,
   pub keys_idx: usize
 }
@@ -159,7 +179,11 @@ impl Koneko {
       (b'&', Token::Ampersand),
       (b'!', Token::Exclamation),
       (b'%', Token::Percent),
+      (b'^', Token::Caret),
       (b',', Token::Comma),
+      (b':', Token::Colon),
+      (b';', Token::Semicolon),
+      (b'.', Token::Dot),
     ]);
 
     let keywords = HashMap::from([
@@ -199,11 +223,132 @@ impl Koneko {
         "new",
         "rim",
         "text",
+        "check",
+        "len",
+        "mid$",
+        "left$",
+        "right$",
+        "instr",
+        "upper$",
+        "lower$",
+        "trim$",
+        "replace$",
+        "asc",
+        "val",
+        "split",
+        "join",
+        "tan",
+        "atan2",
+        "sqrt",
+        "abs",
+        "floor",
+        "ceil",
+        "sgn",
+        "min",
+        "max",
+        "clamp",
+        "lerp",
+        "exp",
+        "log",
+        "band",
+        "bor",
+        "bxor",
+        "bnot",
+        "shl",
+        "shr",
+        "push",
+        "pop",
+        "insert",
+        "remove",
+        "slice",
+        "reverse",
+        "sort",
+        "find",
+        "concat",
+        "keys",
+        "has",
+        "delete",
       ],
       builtin_vars: vec![
         "time",
         "inkey$"
-      ]
+      ],
+      builtin_arities: HashMap::from([
+        ("print", (1, 1)),
+        ("next", (1, 1)),
+        ("loop", (0, 0)),
+        ("while", (1, 1)),
+        ("sin", (1, 1)),
+        ("cos", (1, 1)),
+        ("goto", (1, 1)),
+        ("gosub", (1, 1)),
+        ("end", (0, 0)),
+        ("ret", (0, 0)),
+        ("dot", (3, 3)),
+        ("time", (0, 0)),
+        ("cls", (0, 1)),
+        ("delay", (1, 1)),
+        ("refresh", (0, 0)),
+        ("poly", (2, usize::MAX)),
+        ("line", (3, 3)),
+        ("str", (1, 1)),
+        ("int", (1, 1)),
+        ("chr", (1, 1)),
+        ("rnd", (2, 2)),
+        ("rad", (1, 1)),
+        ("deg", (1, 1)),
+        ("save", (1, 1)),
+        ("load", (1, 1)),
+        ("new", (0, 0)),
+        ("rim", (2, usize::MAX)),
+        ("text", (4, 6)),
+        ("check", (0, 0)),
+        ("len", (1, 1)),
+        ("mid$", (2, 3)),
+        ("left$", (2, 2)),
+        ("right$", (2, 2)),
+        ("instr", (2, 3)),
+        ("upper$", (1, 1)),
+        ("lower$", (1, 1)),
+        ("trim$", (1, 1)),
+        ("replace$", (3, 3)),
+        ("asc", (1, 1)),
+        ("val", (1, 1)),
+        ("split", (2, 2)),
+        ("join", (2, 2)),
+        ("tan", (1, 1)),
+        ("atan2", (2, 2)),
+        ("sqrt", (1, 1)),
+        ("abs", (1, 1)),
+        ("floor", (1, 1)),
+        ("ceil", (1, 1)),
+        ("sgn", (1, 1)),
+        ("min", (2, usize::MAX)),
+        ("max", (2, usize::MAX)),
+        ("clamp", (3, 3)),
+        ("lerp", (3, 3)),
+        ("exp", (1, 1)),
+        ("log", (1, 1)),
+        ("band", (2, 2)),
+        ("bor", (2, 2)),
+        ("bxor", (2, 2)),
+        ("bnot", (1, 1)),
+        ("shl", (2, 2)),
+        ("shr", (2, 2)),
+        ("push", (2, usize::MAX)),
+        ("pop", (1, 1)),
+        ("insert", (3, 3)),
+        ("remove", (2, 2)),
+        ("slice", (2, 3)),
+        ("reverse", (1, 1)),
+        ("sort", (1, 1)),
+        ("find", (2, 2)),
+        ("concat", (2, usize::MAX)),
+        ("keys", (1, 1)),
+        ("has", (2, 2)),
+        ("delete", (2, 2)),
+        ("inkey$", (0, 0)),
+      ]),
     };
 
     let mut ko = Koneko {
@@ -221,7 +366,10 @@ impl Koneko {
       line_scroll: 0,
       prev_cursor_on: false,
       error: None,
+      error_at: None,
       ok: None,
+      findings: vec![],
+      input: None,
       keys_down: Vec::new(),
       keys_idx: 0
     };
@@ -243,7 +391,15 @@ impl Koneko {
           Some(Keycode::Tab) => {
             let line_cursor_valid = self.line_cursor < self.basic.program.len() as i32;
             if keymod.contains(sdl2::keyboard::Mod::LCTRLMOD) {
-              self.screen = (self.screen + 1) % 2;
+              // problems the checker can find are reported up front instead of when execution reaches them,
+              // and only errors keep the program from starting
+              if self.screen == BASIC_SCREEN && self.check() > 0 {
+                self.error = Some(format!("Found {} problems", self.findings.len()));
+                self.ok = None;
+              } else {
+                self.screen = (self.screen + 1) % 2;
+                self.input = None;
+              }
               self.redraw_screen();
             } else if line_cursor_valid {
               self.current_line = self.basic.program[self.line_cursor as usize].contents.clone();
@@ -251,41 +407,41 @@ impl Koneko {
             }
           }
           Some(Keycode::Backspace) => {
-            if self.screen == BASIC_SCREEN {
-              if self.cursor > 0 {
-                self.current_line.remove(self.cursor as usize - 1);
-                self.cursor -= 1;
+            if let Some((line, cursor)) = self.editing() {
+              if let Some(prev) = Self::prev_char_boundary(line, *cursor) {
+                line.remove(prev);
+                *cursor = prev as i32;
               }
             }
           }
           Some(Keycode::Delete) => {
-            if self.screen == BASIC_SCREEN {
-              if self.cursor < self.current_line.len() as i32 {
-                self.current_line.remove(self.cursor as usize);
+            if let Some((line, cursor)) = self.editing() {
+              if *cursor < line.len() as i32 {
+                line.remove(*cursor as usize);
               }
             }
           }
           Some(Keycode::Home) => {
-            if self.screen == BASIC_SCREEN {
-              self.cursor = 0;
+            if let Some((_, cursor)) = self.editing() {
+              *cursor = 0;
             }
           }
           Some(Keycode::End) => {
-            if self.screen == BASIC_SCREEN {
-              self.cursor = self.current_line.len() as i32;
+            if let Some((line, cursor)) = self.editing() {
+              *cursor = line.len() as i32;
             }
           }
           Some(Keycode::Left) => {
-            if self.screen == BASIC_SCREEN {
-              if self.cursor > 0 {
-                self.cursor -= 1;
+            if let Some((line, cursor)) = self.editing() {
+              if let Some(prev) = Self::prev_char_boundary(line, *cursor) {
+                *cursor = prev as i32;
               }
             }
           }
           Some(Keycode::Right) => {
-            if self.screen == BASIC_SCREEN {
-              if self.cursor < self.current_line.len() as i32 {
-                self.cursor += 1;
+            if let Some((line, cursor)) = self.editing() {
+              if let Some(char) = line[*cursor as usize..].chars().next() {
+                *cursor += char.len_utf8() as i32;
               }
             }
           }
@@ -311,14 +467,17 @@ impl Koneko {
           Some(Keycode::Return) => {
             if self.screen == BASIC_SCREEN {
               self.error = None;
+              self.error_at = None;
               self.ok = None;
+              self.findings.clear();
               let res = self.basic.add_line(self.current_line.clone());
               if let Err(error) = res {
-                self.error = Some(error);
-              } else if let Ok(Some(node)) = res {
-                let res = self.interpret(node);
+                self.error = Some(error.to_string());
+                self.error_at = error.span().map(|span| (self.current_line.clone(), span));
+              } else if let Ok(Some(nodes)) = res {
+                let res = self.run_immediate(nodes);
                 if let Err(error) = res {
-                  self.error = Some(error);
+                  self.error = Some(error.to_string());
                 } else if let Ok(value) = res {
                   self.ok = Some(self.current_line.clone() + " -> " + value.to_string(true).as_str());
                 }
@@ -330,6 +489,9 @@ impl Koneko {
                 self.cursor = 0;
               }
               self.redraw_screen();
+            } else if let Some(input) = &mut self.input {
+              input.done = true;
+              self.draw_input(false);
             }
           }
           _ => {}
@@ -350,12 +512,27 @@ impl Koneko {
     }
   }
   
+  // the cursor is a byte offset into the line, so it moves a whole char at a time
+  fn prev_char_boundary(line: &str, cursor: i32) -> Option<usize> {
+    line[..cursor as usize].char_indices().next_back().map(|(idx, _)| idx)
+  }
+
+  // the line being typed in and its cursor, either at the BASIC prompt or for a program's input
+  fn editing(&mut self) -> Option<(&mut String, &mut i32)> {
+    if self.screen == BASIC_SCREEN {
+      return Some((&mut self.current_line, &mut self.cursor));
+    }
+    self.input.as_mut().filter(|it| !it.done).map(|it| (&mut it.line, &mut it.cursor))
+  }
+
   pub fn on_text_input(&mut self, event: Event) {
     if let Event::TextInput { text, .. } = event {
+      if let Some((line, cursor)) = self.editing() {
+        line.insert_str(*cursor as usize, text.as_str());
+        *cursor += text.len() as i32;
+      }
       if self.screen == BASIC_SCREEN {
-        self.current_line.insert_str(self.cursor as usize, text.as_str());
         self.current_line_highlighted = self.highlight_string(self.current_line.clone().to_string());
-        self.cursor += text.len() as i32;
       }
     }
   }
@@ -365,8 +542,15 @@ impl Koneko {
       Token::To | Token::Step | Token::Then | Token::Else => Sweetie16::Pink,
       Token::Integer(_) | Token::Float(_) => Sweetie16::Orange,
       Token::String(_) => Sweetie16::LightGreen,
+      Token::Label(_) => Sweetie16::LightBlue,
+      Token::Comment(_) => Sweetie16::MediumGray,
       Token::Identifier(id) => {
-        if self.basic.is_builtin_command(id.as_str()) || id == "for" || id == "if" {
+        let is_keyword = matches!(
+          id.as_str(),
+          "for" | "if" | "elseif" | "def" | "return" | "local" | "on" | "error" | "resume" | "dim"
+            | "type" | "data" | "read" | "restore" | "input"
+        );
+        if self.basic.is_builtin_command(id.as_str()) || is_keyword {
           Sweetie16::Pink
         } else {
           Sweetie16::Yellow
@@ -420,6 +604,22 @@ impl Koneko {
           self.text(display.as_str(), 3, 3 + (i - self.line_scroll) * 12, Sweetie16::White, None::<u8>, None::<u8>)
         }
 
+        // findings are listed upwards from the status line, over the end of the listing
+        let mut findings = self.findings.clone();
+        if findings.len() > MAX_FINDINGS {
+          let more = findings.len() - MAX_FINDINGS + 1;
+          findings.truncate(MAX_FINDINGS - 1);
+          findings.push(format!("... and {} more", more));
+        }
+
+        let top = HEIGHT - 27 - findings.len() as i32 * 12;
+        if !findings.is_empty() {
+          self.rect(0, top - 3, WIDTH, findings.len() as i32 * 12, Sweetie16::Black);
+        }
+        for (i, finding) in findings.iter().enumerate() {
+          self.text(finding.as_str(), 3, top + i as i32 * 12, Sweetie16::Red, None::<u8>, None::<u8>)
+        }
+
         if let Some(error) = &self.error {
           self.text(error.clone().as_str(), 3, HEIGHT - 27, Sweetie16::Red, None::<u8>, None::<u8>)
         } else if let Some(ok) = &self.ok {
@@ -448,6 +648,14 @@ impl Koneko {
           None::<u8>,
         );
 
+        if let Some((line, (begin, end))) = &self.error_at {
+          if *line == self.current_line {
+            let x = 3 + self.width(&format!("basic: {}", &line[..*begin]));
+            let width = self.width(&line[*begin..*end]).max(5);
+            self.rect(x, HEIGHT - 2, width, 1, Sweetie16::Red);
+          }
+        }
+
         let cursor_on = millis() % 1000 < 500;
         if cursor_on {
           self.text(
@@ -460,19 +668,59 @@ impl Koneko {
           );
         }
       }
-      EXEC_SCREEN => {}
+      EXEC_SCREEN => {
+        if self.input.as_ref().is_some_and(|it| !it.done) {
+          self.draw_input(millis() % 1000 < 500);
+        }
+      }
       _ => panic!("Unknown screen {}", self.screen)
     }
   }
 
-  pub fn execute_code(&mut self) -> Result<(), String> {
+  // prints the prompt and waits for a line to be typed in after it
+  pub fn start_input(&mut self, prompt: String) {
+    self.print(prompt);
+    self.input = Some(Input {
+      prompt: self.printed_text.last().cloned().unwrap_or_default(),
+      line: String::new(),
+      cursor: 0,
+      row: self.printed_text.len() - 1,
+      done: false,
+    });
+  }
+
+  fn draw_input(&mut self, cursor_on: bool) {
+    let Some(input) = &self.input else {
+      return;
+    };
+
+    let text = input.prompt.clone() + input.line.as_str();
+    let before_cursor = input.prompt.clone() + &input.line[..input.cursor as usize];
+    let y = input.row as i32 * 12 + 2;
+    self.printed_text[input.row] = text.clone();
+
+    self.rect(0, y - 1, WIDTH, 12, Sweetie16::Black);
+    self.text(text.as_str(), 2, y, Sweetie16::White, Some(Sweetie16::DarkGray), Some(Sweetie16::Black));
+    if cursor_on {
+      self.text("_", 2 + self.width(before_cursor.as_str()), y + 1, Sweetie16::White, None::<u8>, None::<u8>);
+    }
+  }
+
+  // runs the checker over the program, listing what it finds on the BASIC screen, and returns
+  // how many of the findings are errors rather than warnings
+  pub fn check(&mut self) -> usize {
+    let findings = self.basic.check();
+    let errors = findings.iter().filter(|(severity, _)| *severity == Severity::Error).count();
+    self.findings = findings.into_iter().map(|(_, finding)| finding).collect();
+    errors
+  }
+
+  pub fn execute_code(&mut self) -> Result<(), KonekoError> {
     if self.screen == EXEC_SCREEN {
       let begin = millis();
-      while !self.basic.refresh && millis() - begin < 2000 {
-        if self.basic.line_no >= self.basic.program.len() {
-          break;
-        }
-        self.exec_current_line()?;
+      if let Err(error) = self.run_program(begin) {
+        self.show_error(&error);
+        return Err(error);
       }
       self.keys_idx = 0;
 
@@ -487,7 +735,37 @@ impl Koneko {
     Ok(())
   }
 
+  // stops the program and shows a runtime error in the BASIC screen's error bar, bringing up the
+  // line it failed on with the statement underlined
+  pub fn show_error(&mut self, error: &KonekoError) {
+    self.screen = BASIC_SCREEN;
+    self.input = None;
+    self.error = Some(error.to_string());
+    self.error_at = None;
+    self.ok = None;
+
+    if let Some(&idx) = error.line_no().and_then(|line_no| self.basic.line_indices.get(&line_no)) {
+      let line = self.basic.program[idx].contents.clone();
+      self.line_cursor = idx as i32;
+      if self.line_cursor < self.line_scroll || self.line_cursor >= self.line_scroll + BASIC_TEXT_HEIGHT {
+        self.line_scroll = self.line_cursor;
+      }
+      self.error_at = error.span().map(|span| (line.clone(), span));
+      self.cursor = line.len() as i32;
+      self.current_line_highlighted = self.highlight_string(line.clone());
+      self.current_line = line;
+    }
+    self.redraw_screen();
+  }
+
+  // a string can hold newlines of its own, each starting a new line on screen
   pub fn print(&mut self, text: String) {
+    for line in text.split('\n') {
+      self.print_line(line.to_string());
+    }
+  }
+
+  fn print_line(&mut self, text: String) {
     if self.printed_text.len() + 1 > TEXT_HEIGHT as usize {
       // redraw whole text screen
       self.printed_text.remove(0);
@@ -1,19 +1,23 @@
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::path::Path;
+use std::rc::Rc;
 use std::thread::sleep;
 use std::time::Duration;
 use rand::Rng;
 use std::io::{Read, Write};
-use crate::lex_parse_basic::{Node, Token, Value};
-use crate::koneko::{Koneko, secs_since_start};
+use crate::compile_basic::Op;
+use crate::lex_parse_basic::{Frame, Key, MAX_ARRAY_LEN, MAX_CALL_DEPTH, Node, Token, Value};
+use crate::koneko::{Koneko, millis, secs_since_start};
+use crate::koneko_error::{ErrorKind, KonekoError};
 use crate::palette::Sweetie16;
 
 impl Koneko {
-  pub fn vec2i_from_value(value: &Value) -> Result<(i32, i32), String> {
+  pub fn vec2i_from_value(value: &Value) -> Result<(i32, i32), KonekoError> {
     Ok(match value {
       Value::Array(array) => {
         if array.len() != 2 {
-          return Err(format!("Expected array of length 2, got {:?}", array));
+          return Err(KonekoError::runtime(ErrorKind::InvalidArgument, format!("Expected array of length 2, got {:?}", array)));
         }
 
         let x = array[0].to_integer()? as i32;
@@ -21,13 +25,17 @@ impl Koneko {
 
         (x, y)
       }
-      _ => return Err(format!("Expected array, got {:?}", value))
+      _ => return Err(KonekoError::runtime(ErrorKind::TypeMismatch, format!("Expected array, got {:?}", value)))
     })
   }
 
-  pub fn palette_idx_from_value(value: &Value) -> Result<u8, String> {
+  pub fn palette_idx_from_value(value: &Value) -> Result<u8, KonekoError> {
     Ok(match value {
-      Value::Integer(num) => *num as u8,
+      Value::Integer(num @ 0..=15) => *num as u8,
+      Value::Integer(num) => {
+        let message = format!("Expected color between 0 and 15, got {}", num);
+        return Err(KonekoError::runtime(ErrorKind::InvalidArgument, message));
+      }
       Value::String(str) => {
         match str.as_str() {
           "orange" | "org" => Sweetie16::Orange.into(),
@@ -46,682 +54,2365 @@ impl Koneko {
           "purple" | "pur" => Sweetie16::Purple.into(),
           "black" | "blk" => Sweetie16::Black.into(),
           "white" | "wht" => Sweetie16::White.into(),
-          _ => return Err(format!("Unknown color {}", str))
+          _ => return Err(KonekoError::runtime(ErrorKind::InvalidArgument, format!("Unknown color {}", str)))
         }
       }
-      _ => return Err(format!("Expected integer, got {:?}", value))
+      _ => return Err(KonekoError::runtime(ErrorKind::TypeMismatch, format!("Expected integer, got {:?}", value)))
     })
   }
 
-  fn expect_n_args(args: &[Node], n: usize) -> Result<(), String> {
+  pub fn string_from_value(value: &Value) -> Result<&str, KonekoError> {
+    match value {
+      Value::String(string) => Ok(string),
+      _ => Err(KonekoError::runtime(ErrorKind::TypeMismatch, format!("Expected string, got {:?}", value)))
+    }
+  }
+
+  pub fn integer_from_value(value: &Value) -> Result<i64, KonekoError> {
+    match value {
+      Value::Integer(num) => Ok(*num),
+      _ => Err(KonekoError::runtime(ErrorKind::TypeMismatch, format!("Expected integer, got {:?}", value)))
+    }
+  }
+
+  pub fn float_from_value(value: &Value) -> Result<f64, KonekoError> {
+    match value {
+      Value::Integer(num) => Ok(*num as f64),
+      Value::Float(num) => Ok(*num),
+      _ => Err(KonekoError::runtime(ErrorKind::TypeMismatch, format!("Expected integer or float, got {:?}", value)))
+    }
+  }
+
+  // the arguments as integers if they all are, for builtins that only promote to float when they have to
+  fn integers_from_values(values: &[Value]) -> Option<Vec<i64>> {
+    values.iter()
+      .map(|value| match value {
+        Value::Integer(num) => Some(*num),
+        _ => None,
+      })
+      .collect()
+  }
+
+  pub fn count_from_value(value: &Value) -> Result<usize, KonekoError> {
+    let count = value.to_integer()?;
+    if count < 0 {
+      return Err(KonekoError::runtime(ErrorKind::InvalidArgument, format!("Expected non-negative integer, got {}", count)));
+    }
+    Ok(count as usize)
+  }
+
+  fn array_index(array: &[Value], index: &Value) -> Result<usize, KonekoError> {
+    let index = Self::integer_from_value(index)?;
+    if index < 0 || index as usize >= array.len() {
+      let message = format!("Index {} out of bounds for array of length {}", index, array.len());
+      return Err(KonekoError::runtime(ErrorKind::OutOfBounds, message));
+    }
+    Ok(index as usize)
+  }
+
+  fn missing_key(key: &Key) -> KonekoError {
+    KonekoError::runtime(ErrorKind::OutOfBounds, format!("Key {} not found in map", key.to_value().to_string(true)))
+  }
+
+  // follows value[index1]...[indexN] down through nested arrays, maps and records
+  fn element<'a>(mut value: &'a Value, indices: &[Value]) -> Result<&'a Value, KonekoError> {
+    for index in indices {
+      value = match value {
+        Value::Array(array) => &array[Self::array_index(array, index)?],
+        Value::Map(map) => {
+          let key = Key::from_value(index)?;
+          map.get(&key).ok_or_else(|| Self::missing_key(&key))?
+        }
+        Value::Record(record, values) => &values[record.field(index)?],
+        _ => return Err(KonekoError::runtime(ErrorKind::TypeMismatch, format!("Expected array, map or record, got {:?}", value)))
+      };
+    }
+    Ok(value)
+  }
+
+  fn element_mut<'a>(mut value: &'a mut Value, indices: &[Value]) -> Result<&'a mut Value, KonekoError> {
+    for index in indices {
+      value = match value {
+        Value::Array(array) => {
+          let index = Self::array_index(array, index)?;
+          &mut array[index]
+        }
+        Value::Map(map) => {
+          let key = Key::from_value(index)?;
+          map.get_mut(&key).ok_or_else(|| Self::missing_key(&key))?
+        }
+        Value::Record(record, values) => &mut values[record.field(index)?],
+        _ => return Err(KonekoError::runtime(ErrorKind::TypeMismatch, format!("Expected array, map or record, got {:?}", value)))
+      };
+    }
+    Ok(value)
+  }
+
+  fn empty_array(sizes: &[usize]) -> Result<Value, KonekoError> {
+    let len = sizes.iter().try_fold(1usize, |len, size| len.checked_mul(*size));
+    if len.is_none_or(|len| len > MAX_ARRAY_LEN) {
+      let message = format!("Array {:?} is too large; at most {} elements in all", sizes, MAX_ARRAY_LEN);
+      return Err(KonekoError::runtime(ErrorKind::Overflow, message));
+    }
+    Ok(Self::nils(sizes))
+  }
+
+  fn nils(sizes: &[usize]) -> Value {
+    match sizes.split_first() {
+      Some((size, rest)) => Value::Array(vec![Self::nils(rest); *size]),
+      None => Value::Nil,
+    }
+  }
+
+  fn expect_n_args(args: &[Value], n: usize) -> Result<(), KonekoError> {
     if args.len() != n {
-      return Err(format!("Expected {} arguments, got {}", n, args.len()));
+      return Err(KonekoError::runtime(ErrorKind::ArgumentCount, format!("Expected {} arguments, got {}", n, args.len())));
     }
     Ok(())
   }
 
-  pub fn interpret(&mut self, node: Node) -> Result<Value, String> {
-    match node {
-      Node::Integer(num) => Ok(Value::Integer(num)),
-      Node::Float(num) => Ok(Value::Float(num)),
-      Node::String(string) => Ok(Value::String(string.clone())),
-      Node::VarGet(name) => {
-        if let Some(value) = self.basic.vars.get(name.as_str()) {
-          Ok(value.clone())
-        } else {
-          Err(format!("Variable {} not found!", name))
+  fn expect_args_between(args: &[Value], min: usize, max: usize) -> Result<(), KonekoError> {
+    if args.len() < min || args.len() > max {
+      return Err(KonekoError::runtime(ErrorKind::ArgumentCount, format!("Expected {} to {} arguments, got {}", min, max, args.len())));
+    }
+    Ok(())
+  }
+
+  // integer arithmetic, failing instead of overflowing or dividing by zero
+  fn integer_op(op: &Token, left: i64, right: i64) -> Result<Value, KonekoError> {
+    let (result, symbol) = match op {
+      Token::Add => (left.checked_add(right), "+"),
+      Token::Sub => (left.checked_sub(right), "-"),
+      Token::Mul => (left.checked_mul(right), "*"),
+      Token::Div => (left.checked_div(right), "/"),
+      Token::Percent => (left.checked_rem(right), "%"),
+      _ => return Err(KonekoError::runtime(ErrorKind::TypeMismatch, format!("Unknown integer operator {:?}", op))),
+    };
+
+    if right == 0 && matches!(op, Token::Div | Token::Percent) {
+      return Err(KonekoError::runtime(ErrorKind::DivisionByZero, format!("Division by zero in {} {} 0", left, symbol)));
+    }
+    result.map(Value::Integer).ok_or_else(|| {
+      KonekoError::runtime(ErrorKind::Overflow, format!("Integer overflow in {} {} {}", left, symbol, right))
+    })
+  }
+
+  fn binary_op(op: &Token, left: Value, right: Value) -> Result<Value, KonekoError> {
+    match op {
+      Token::Add => {
+        match (&left, &right) {
+          (Value::Integer(left), Value::Integer(right)) => Self::integer_op(op, *left, *right),
+          (Value::Float(left), Value::Float(right)) => Ok(Value::Float(left + right)),
+          (Value::Integer(left), Value::Float(right)) => Ok(Value::Float(*left as f64 + right)),
+          (Value::Float(left), Value::Integer(right)) => Ok(Value::Float(left + *right as f64)),
+          (Value::String(left), Value::String(right)) =>
+            Ok(Value::String(left.clone() + right.clone().as_str())),
+          _ => Err(KonekoError::runtime(ErrorKind::TypeMismatch, format!("Cannot compare {:?} and {:?} with op {:?}", left, right, op)))
         }
       }
-      Node::Assign { name, value } => {
-        let value = self.interpret(*value)?;
-        self.basic.vars.insert(name.clone(), value.clone());
-        Ok(value)
+      Token::Sub => {
+        match (&left, &right) {
+          (Value::Integer(left), Value::Integer(right)) => Self::integer_op(op, *left, *right),
+          (Value::Float(left), Value::Float(right)) => Ok(Value::Float(left - right)),
+          (Value::Integer(left), Value::Float(right)) => Ok(Value::Float(*left as f64 - right)),
+          (Value::Float(left), Value::Integer(right)) => Ok(Value::Float(left - *right as f64)),
+          _ => Err(KonekoError::runtime(ErrorKind::TypeMismatch, format!("Cannot compare {:?} and {:?} with op {:?}", left, right, op)))
+        }
       }
-      Node::For { name, start, end, step } => {
-        if let Some(_value) = self.basic.vars.get(name.as_str()) {
-          return Err(format!("Variable {} already exists!", name));
+      Token::Percent => {
+        match (&left, &right) {
+          (Value::Integer(left), Value::Integer(right)) => Self::integer_op(op, *left, *right),
+          (Value::Float(left), Value::Float(right)) => Ok(Value::Float(left % right)),
+          (Value::Integer(left), Value::Float(right)) => Ok(Value::Float(*left as f64 % right)),
+          (Value::Float(left), Value::Integer(right)) => Ok(Value::Float(left % *right as f64)),
+          _ => Err(KonekoError::runtime(ErrorKind::TypeMismatch, format!("Cannot compare {:?} and {:?} with op {:?}", left, right, op)))
         }
-
-        let start = self.interpret(*start)?;
-        let end = self.interpret(*end)?;
-        let step = self.interpret(*step)?;
-
-        self.basic.vars.insert(name.clone(), start.clone());
-        self.basic.for_stack.push((self.basic.line_no, end, step));
-
-        Ok(Value::Nil)
       }
-      Node::If { cond, then, else_ } => {
-        let cond = self.interpret(*cond)?;
-        if cond.is_truthy() {
-          self.interpret(*then)
-        } else {
-          self.interpret(*else_)
-        }
-      }
-      Node::BinOp { op, left, right } => {
-        let left = self.interpret(*left)?;
-        let right = self.interpret(*right)?;
-
-        match op {
-          Token::Add => {
-            match (&left, &right) {
-              (Value::Integer(left), Value::Integer(right)) => Ok(Value::Integer(left + right)),
-              (Value::Float(left), Value::Float(right)) => Ok(Value::Float(left + right)),
-              (Value::Integer(left), Value::Float(right)) => Ok(Value::Float(*left as f64 + right)),
-              (Value::Float(left), Value::Integer(right)) => Ok(Value::Float(left + *right as f64)),
-              (Value::String(left), Value::String(right)) =>
-                Ok(Value::String(left.clone() + right.clone().as_str())),
-              _ => Err(format!("Cannot compare {:?} and {:?} with op {:?}", left, right, op))
-            }
-          }
-          Token::Sub => {
-            match (&left, &right) {
-              (Value::Integer(left), Value::Integer(right)) => Ok(Value::Integer(left - right)),
-              (Value::Float(left), Value::Float(right)) => Ok(Value::Float(left - right)),
-              (Value::Integer(left), Value::Float(right)) => Ok(Value::Float(*left as f64 - right)),
-              (Value::Float(left), Value::Integer(right)) => Ok(Value::Float(left - *right as f64)),
-              _ => Err(format!("Cannot compare {:?} and {:?} with op {:?}", left, right, op))
-            }
-          }
-          Token::Percent => {
-            match (&left, &right) {
-              (Value::Integer(left), Value::Integer(right)) => Ok(Value::Integer(left % right)),
-              (Value::Float(left), Value::Float(right)) => Ok(Value::Float(left % right)),
-              (Value::Integer(left), Value::Float(right)) => Ok(Value::Float(*left as f64 % right)),
-              (Value::Float(left), Value::Integer(right)) => Ok(Value::Float(left % *right as f64)),
-              _ => Err(format!("Cannot compare {:?} and {:?} with op {:?}", left, right, op))
-            }
-          }
-          Token::Mul => {
-            match (&left, &right) {
-              (Value::Integer(left), Value::Integer(right)) => Ok(Value::Integer(left * right)),
-              (Value::Float(left), Value::Float(right)) => Ok(Value::Float(left * right)),
-              (Value::Integer(left), Value::Float(right)) => Ok(Value::Float(*left as f64 * right)),
-              (Value::Float(left), Value::Integer(right)) => Ok(Value::Float(left * *right as f64)),
-              _ => Err(format!("Cannot compare {:?} and {:?} with op {:?}", left, right, op))
-            }
-          }
-          Token::Div => {
-            match (&left, &right) {
-              (Value::Integer(left), Value::Integer(right)) => Ok(Value::Integer(left / right)),
-              (Value::Float(left), Value::Float(right)) => Ok(Value::Float(left / right)),
-              (Value::Integer(left), Value::Float(right)) => Ok(Value::Float(*left as f64 / right)),
-              (Value::Float(left), Value::Integer(right)) => Ok(Value::Float(left / *right as f64)),
-              _ => Err(format!("Cannot compare {:?} and {:?} with op {:?}", left, right, op))
-            }
-          }
-          Token::Lt => {
-            Ok(Value::Integer((left.comparison_value()? < right.comparison_value()?) as i64))
-          }
-          Token::Gt => {
-            Ok(Value::Integer((left.comparison_value()? > right.comparison_value()?) as i64))
-          }
-          Token::Gte => {
-            Ok(
-              Value::Integer(
-                (left.comparison_value()? > right.comparison_value()? ||
-                  (left.comparison_value()? - right.comparison_value()?).abs() < 0.0000001) as i64))
-          }
-          Token::Lte => {
-            Ok(
-              Value::Integer(
-                (left.comparison_value()? < right.comparison_value()? ||
-                  (left.comparison_value()? - right.comparison_value()?).abs() < 0.0000001) as i64))
-          }
-          Token::EqEq => {
-            Ok(
-              Value::Integer(
-                (left == right) as i64))
-          }
-          Token::Ampersand => {
-            let lhs = left.is_truthy();
-            let rhs = right.is_truthy();
-            Ok(Value::Integer((lhs && rhs) as i64))
-          }
-          Token::Pipe => {
-            let lhs = left.is_truthy();
-            let rhs = right.is_truthy();
-            Ok(Value::Integer((lhs || rhs) as i64))
-          }
-          _ => Err(format!("Cannot compare {:?} and {:?} with op {:?}", left, right, op))
+      Token::Mul => {
+        match (&left, &right) {
+          (Value::Integer(left), Value::Integer(right)) => Self::integer_op(op, *left, *right),
+          (Value::Float(left), Value::Float(right)) => Ok(Value::Float(left * right)),
+          (Value::Integer(left), Value::Float(right)) => Ok(Value::Float(*left as f64 * right)),
+          (Value::Float(left), Value::Integer(right)) => Ok(Value::Float(left * *right as f64)),
+          _ => Err(KonekoError::runtime(ErrorKind::TypeMismatch, format!("Cannot compare {:?} and {:?} with op {:?}", left, right, op)))
         }
       }
-      Node::UnOp { op, right } => {
-        match op {
-          Token::Exclamation => {
-            let right = self.interpret(*right)?;
-            Ok(Value::Integer(!right.is_truthy() as i64))
-          }
-          Token::Sub => {
-            let right = self.interpret(*right)?;
-            match right {
-              Value::Integer(num) => Ok(Value::Integer(-num)),
-              Value::Float(num) => Ok(Value::Float(-num)),
-              _ => Err(format!("Cannot negate {:?}", right))
+      Token::Div => {
+        match (&left, &right) {
+          (Value::Integer(left), Value::Integer(right)) => Self::integer_op(op, *left, *right),
+          (Value::Float(left), Value::Float(right)) => Ok(Value::Float(left / right)),
+          (Value::Integer(left), Value::Float(right)) => Ok(Value::Float(*left as f64 / right)),
+          (Value::Float(left), Value::Integer(right)) => Ok(Value::Float(left / *right as f64)),
+          _ => Err(KonekoError::runtime(ErrorKind::TypeMismatch, format!("Cannot compare {:?} and {:?} with op {:?}", left, right, op)))
+        }
+      }
+      Token::Caret => {
+        match (&left, &right) {
+          (Value::Integer(left), Value::Integer(right)) => {
+            if *right >= 0 {
+              return u32::try_from(*right).ok()
+                .and_then(|right| left.checked_pow(right))
+                .map(Value::Integer)
+                .ok_or_else(|| KonekoError::runtime(ErrorKind::Overflow, format!("Integer overflow in {} ^ {}", left, right)));
             }
-          }
-          Token::Add => {
-            let right = self.interpret(*right)?;
-            match right {
-              Value::Integer(num) => Ok(Value::Integer(num)),
-              Value::Float(num) => Ok(Value::Float(num)),
-              _ => Err(format!("Cannot negate {:?}", right))
+
+            // the integer part of 1 / left ^ -right, like integer division
+            match left {
+              0 => Err(KonekoError::runtime(ErrorKind::DivisionByZero, format!("Cannot raise 0 to negative power {}", right))),
+              1 => Ok(Value::Integer(1)),
+              -1 => Ok(Value::Integer(if right % 2 == 0 { 1 } else { -1 })),
+              _ => Ok(Value::Integer(0)),
             }
           }
-          _ => {
-            return Err(format!("Unknown unary operator {:?}", op));
-          }
+          (Value::Float(left), Value::Float(right)) => Ok(Value::Float(left.powf(*right))),
+          (Value::Integer(left), Value::Float(right)) => Ok(Value::Float((*left as f64).powf(*right))),
+          (Value::Float(left), Value::Integer(right)) => Ok(Value::Float(left.powf(*right as f64))),
+          _ => Err(KonekoError::runtime(ErrorKind::TypeMismatch, format!("Cannot compare {:?} and {:?} with op {:?}", left, right, op)))
         }
       }
-      Node::BuiltinCommand { name, args } => {
-        match name.as_str() {
-          "refresh" => {
-            Self::expect_n_args(&args, 0)?;
-            self.basic.refresh = true;
-            Ok(Value::Nil)
-          }
-          "rnd" => {
-            Self::expect_n_args(&args, 2)?;
-            let mut rng = rand::thread_rng();
+      Token::Lt => {
+        Ok(Value::Integer((left.comparison_value()? < right.comparison_value()?) as i64))
+      }
+      Token::Gt => {
+        Ok(Value::Integer((left.comparison_value()? > right.comparison_value()?) as i64))
+      }
+      Token::Gte => {
+        Ok(
+          Value::Integer(
+            (left.comparison_value()? > right.comparison_value()? ||
+              (left.comparison_value()? - right.comparison_value()?).abs() < 0.0000001) as i64))
+      }
+      Token::Lte => {
+        Ok(
+          Value::Integer(
+            (left.comparison_value()? < right.comparison_value()? ||
+              (left.comparison_value()? - right.comparison_value()?).abs() < 0.0000001) as i64))
+      }
+      Token::EqEq => {
+        Ok(
+          Value::Integer(
+            (left == right) as i64))
+      }
+      _ => Err(KonekoError::runtime(ErrorKind::TypeMismatch, format!("Cannot compare {:?} and {:?} with op {:?}", left, right, op)))
+    }
+  }
 
-            let min = self.interpret(args[0].clone())?.to_float()?;
-            let max = self.interpret(args[1].clone())?.to_float()?;
+  fn unary_op(op: &Token, right: Value) -> Result<Value, KonekoError> {
+    match op {
+      Token::Exclamation => {
+        Ok(Value::Integer(!right.is_truthy() as i64))
+      }
+      Token::Sub => {
+        match right {
+          Value::Integer(num) => num.checked_neg()
+            .map(Value::Integer)
+            .ok_or_else(|| KonekoError::runtime(ErrorKind::Overflow, format!("Integer overflow in -({})", num))),
+          Value::Float(num) => Ok(Value::Float(-num)),
+          _ => Err(KonekoError::runtime(ErrorKind::TypeMismatch, format!("Cannot negate {:?}", right)))
+        }
+      }
+      Token::Add => {
+        match right {
+          Value::Integer(num) => Ok(Value::Integer(num)),
+          Value::Float(num) => Ok(Value::Float(num)),
+          _ => Err(KonekoError::runtime(ErrorKind::TypeMismatch, format!("Cannot negate {:?}", right)))
+        }
+      }
+      _ => {
+        Err(KonekoError::runtime(ErrorKind::TypeMismatch, format!("Unknown unary operator {:?}", op)))
+      }
+    }
+  }
 
-            Ok(Value::Float(rng.gen_range(min..max)))
-          }
-          "gosub" => {
-            Self::expect_n_args(&args, 1)?;
-
-            let orig_line_no = self.interpret(args[0].clone())?.to_integer()? as usize;
-            let line_no =
-              self.basic.program.iter().position(|x| x.line_no == orig_line_no)
-                .ok_or(format!("Gosub: Could not find line {}", orig_line_no))?;
-
-            self.basic.call_stack.push(self.basic.line_no);
-            self.basic.line_no = line_no;
-            self.basic.no_increment_instr_counter = true;
-            Ok(Value::Nil)
-          }
-          "delay" => {
-            Self::expect_n_args(&args, 1)?;
+  // builtins that change the array or map given as their first argument, with args holding the rest
+  fn builtin_in_place(name: &str, array: &mut Value, args: Vec<Value>) -> Result<Value, KonekoError> {
+    if name == "delete" {
+      // delete map key, giving back the removed value or nil
+      let Value::Map(map) = array else {
+        return Err(KonekoError::runtime(ErrorKind::TypeMismatch, format!("Expected map, got {:?}", array)));
+      };
+      if args.len() != 1 {
+        return Err(KonekoError::runtime(ErrorKind::ArgumentCount, format!("Expected 2 arguments, got {}", args.len() + 1)));
+      }
 
-            sleep(Duration::from_millis(self.interpret(args[0].clone())?.to_integer()? as u64));
-            Ok(Value::Nil)
-          }
-          "sin" => {
-            Self::expect_n_args(&args, 1)?;
-
-            let value = self.interpret(args[0].clone())?;
-            match value {
-              Value::Integer(num) => Ok(Value::Float((num as f64).sin())),
-              Value::Float(num) => Ok(Value::Float(num.sin())),
-              _ => Err(format!("Expected integer or float, got {:?}", value))
-            }
-          }
-          "cos" => {
-            Self::expect_n_args(&args, 1)?;
-
-            let value = self.interpret(args[0].clone())?;
-            match value {
-              Value::Integer(num) => Ok(Value::Float((num as f64).cos())),
-              Value::Float(num) => Ok(Value::Float(num.cos())),
-              _ => Err(format!("Expected integer or float, got {:?}", value))
-            }
-          }
-          "time" => {
-            Self::expect_n_args(&args, 0)?;
+      return Ok(map.remove(&Key::from_value(&args[0])?).unwrap_or(Value::Nil));
+    }
 
-            let a = secs_since_start();
-            Ok(Value::Float(a))
-          }
-          "end" => {
-            Self::expect_n_args(&args, 0)?;
+    let Value::Array(array) = array else {
+      return Err(KonekoError::runtime(ErrorKind::TypeMismatch, format!("Expected array, got {:?}", array)));
+    };
 
-            self.basic.line_no = self.basic.program.len();
-            Ok(Value::Nil)
-          }
-          "print" => {
-            Self::expect_n_args(&args, 1)?;
+    // counting the array, like every other builtin
+    let expect_n_args = |n: usize| {
+      if args.len() + 1 != n {
+        return Err(KonekoError::runtime(ErrorKind::ArgumentCount, format!("Expected {} arguments, got {}", n, args.len() + 1)));
+      }
+      Ok(())
+    };
 
-            let value = self.interpret(args[0].clone())?;
-            self.print(value.to_string(true));
-            Ok(Value::Nil)
-          }
-          "str" => {
-            Self::expect_n_args(&args, 1)?;
+    match name {
+      "push" => {
+        if args.is_empty() {
+          return Err(KonekoError::runtime(ErrorKind::ArgumentCount, "Expected at least 2 arguments, got 1".to_string()));
+        }
 
-            let value = self.interpret(args[0].clone())?;
-            Ok(Value::String(value.to_string(false)))
-          }
-          "chr" => {
-            Self::expect_n_args(&args, 1)?;
-
-            let value = self.interpret(args[0].clone())?;
-            match value {
-              Value::Integer(num) => {
-                if num < 0 || num > 255 {
-                  return Err(format!("Expected integer between 0 and 255, got {}", num));
-                }
-                Ok(Value::String((num as u8 as char).to_string()))
-              }
-              _ => Err(format!("Expected integer, got {:?}", value))
-            }
-          }
-          "int" => {
-            Self::expect_n_args(&args, 1)?;
+        array.extend(args);
+        Ok(Value::Nil)
+      }
+      "pop" => {
+        expect_n_args(1)?;
 
-            let value = self.interpret(args[0].clone())?;
-            Ok(Value::Integer(value.to_integer_raw()?))
-          }
-          "poly" => {
-            if args.len() < 2 {
-              return Err(format!("Expected at least 2 arguments, got {}", args.len()));
-            }
+        array.pop().ok_or_else(|| KonekoError::runtime(ErrorKind::OutOfBounds, "Cannot pop; array is empty!".to_string()))
+      }
+      "insert" => {
+        // insert array index value, where index may be the length to add at the end
+        expect_n_args(3)?;
+
+        let index = Self::integer_from_value(&args[0])?;
+        if index < 0 || index as usize > array.len() {
+          let message = format!("Index {} out of bounds for array of length {}", index, array.len());
+          return Err(KonekoError::runtime(ErrorKind::OutOfBounds, message));
+        }
 
-            if let Value::Array(elements) = self.interpret(args[0].clone())? {
-              if elements.len() > 2 && args.len() == 2 {
-                let mut points = Vec::<(i32, i32)>::new();
-                for i in 0..elements.len() {
-                  let point = Self::vec2i_from_value(&elements[i].clone())?;
-                  points.push(point);
-                }
-                let color = Self::palette_idx_from_value(&self.interpret(args[1].clone())?)?;
-
-                self.poly(points, color)?;
-                return Ok(Value::Nil);
-              }
-            }
+        array.insert(index as usize, args[1].clone());
+        Ok(Value::Nil)
+      }
+      "remove" => {
+        expect_n_args(2)?;
 
-            if args.len() < 4 {
-              return Err(format!("Expected at least 4 arguments, got {}", args.len()));
-            }
+        let index = Self::array_index(array, &args[0])?;
+        Ok(array.remove(index))
+      }
+      "reverse" => {
+        expect_n_args(1)?;
 
-            let mut points = Vec::<(i32, i32)>::new();
-            for i in 0..args.len() - 1 {
-              let point = Self::vec2i_from_value(&self.interpret(args[i].clone())?)?;
-              points.push(point);
-            }
+        array.reverse();
+        Ok(Value::Nil)
+      }
+      "sort" => {
+        // numbers or strings, but not a mix of the two
+        expect_n_args(1)?;
+
+        let strings = array.iter().all(|it| matches!(it, Value::String(_)));
+        let numbers = array.iter().all(|it| matches!(it, Value::Integer(_) | Value::Float(_)));
+        if !strings && !numbers {
+          let message = format!("Cannot sort {:?}; expected all numbers or all strings", array);
+          return Err(KonekoError::runtime(ErrorKind::TypeMismatch, message));
+        }
 
-            let color = Self::palette_idx_from_value(&self.interpret(args[args.len() - 1].clone())?)?;
+        array.sort_by(|a, b| match (a, b) {
+          (Value::String(a), Value::String(b)) => a.cmp(b),
+          _ => Self::float_from_value(a).unwrap_or(0.0).total_cmp(&Self::float_from_value(b).unwrap_or(0.0)),
+        });
+        Ok(Value::Nil)
+      }
+      _ => {
+        Err(KonekoError::runtime(ErrorKind::UndefinedFunction, format!("Unknown builtin command {}", name)))
+      }
+    }
+  }
 
-            self.poly(points, color)?;
-            Ok(Value::Nil)
-          }
-          "rim" => {
-            if args.len() < 2 {
-              return Err(format!("Expected at least 2 arguments, got {}", args.len()));
-            }
+  fn builtin(&mut self, name: &str, args: Vec<Value>) -> Result<Value, KonekoError> {
+    match name {
+      "refresh" => {
+        Self::expect_n_args(&args, 0)?;
+        self.basic.refresh = true;
+        Ok(Value::Nil)
+      }
+      "rnd" => {
+        Self::expect_n_args(&args, 2)?;
+        let mut rng = rand::thread_rng();
+
+        let min = args[0].to_float()?;
+        let max = args[1].to_float()?;
+        // gen_range panics on an empty or infinite range, and nan fails the first check
+        if !(min < max && (max - min).is_finite()) {
+          let message = format!("Expected min {} to be less than max {}", min, max);
+          return Err(KonekoError::runtime(ErrorKind::InvalidArgument, message));
+        }
 
-            if let Value::Array(elements) = self.interpret(args[0].clone())? {
-              if elements.len() > 2 && args.len() == 2 {
-                let mut points = Vec::<(i32, i32)>::new();
-                for i in 0..elements.len() {
-                  let point = Self::vec2i_from_value(&elements[i])?;
-                  points.push(point);
-                }
-                let color = Self::palette_idx_from_value(&self.interpret(args[1].clone())?)?;
-
-                self.outline(points, color)?;
-                return Ok(Value::Nil);
-              }
-            }
+        Ok(Value::Float(rng.gen_range(min..max)))
+      }
+      "delay" => {
+        Self::expect_n_args(&args, 1)?;
 
-            if args.len() < 4 {
-              return Err(format!("Expected at least 4 arguments, got {}", args.len()));
-            }
+        sleep(Duration::from_millis(args[0].to_integer()? as u64));
+        Ok(Value::Nil)
+      }
+      "sin" => {
+        Self::expect_n_args(&args, 1)?;
 
-            let mut points = Vec::<(i32, i32)>::new();
-            for i in 0..args.len() - 1 {
-              let point = Self::vec2i_from_value(&self.interpret(args[i].clone())?)?;
-              points.push(point);
-            }
+        match args[0] {
+          Value::Integer(num) => Ok(Value::Float((num as f64).sin())),
+          Value::Float(num) => Ok(Value::Float(num.sin())),
+          _ => Err(KonekoError::runtime(ErrorKind::TypeMismatch, format!("Expected integer or float, got {:?}", args[0])))
+        }
+      }
+      "cos" => {
+        Self::expect_n_args(&args, 1)?;
 
-            let color = Self::palette_idx_from_value(&self.interpret(args[args.len() - 1].clone())?)?;
+        match args[0] {
+          Value::Integer(num) => Ok(Value::Float((num as f64).cos())),
+          Value::Float(num) => Ok(Value::Float(num.cos())),
+          _ => Err(KonekoError::runtime(ErrorKind::TypeMismatch, format!("Expected integer or float, got {:?}", args[0])))
+        }
+      }
+      "tan" => {
+        Self::expect_n_args(&args, 1)?;
 
-            self.outline(points, color)?;
-            Ok(Value::Nil)
-          }
-          "line" => {
-            Self::expect_n_args(&args, 3)?;
+        Ok(Value::Float(Self::float_from_value(&args[0])?.tan()))
+      }
+      "atan2" => {
+        // atan2 y x
+        Self::expect_n_args(&args, 2)?;
 
-            let x1y1 = Self::vec2i_from_value(&self.interpret(args[0].clone())?)?;
-            let x2y2 = Self::vec2i_from_value(&self.interpret(args[1].clone())?)?;
-            let color = Self::palette_idx_from_value(&self.interpret(args[2].clone())?)?;
+        let y = Self::float_from_value(&args[0])?;
+        let x = Self::float_from_value(&args[1])?;
+        Ok(Value::Float(y.atan2(x)))
+      }
+      "sqrt" => {
+        Self::expect_n_args(&args, 1)?;
 
-            self.line(x1y1, x2y2, color);
+        let value = Self::float_from_value(&args[0])?;
+        if value < 0.0 {
+          return Err(KonekoError::runtime(ErrorKind::InvalidArgument, format!("Cannot take the square root of negative number {}", value)));
+        }
+        Ok(Value::Float(value.sqrt()))
+      }
+      "abs" => {
+        Self::expect_n_args(&args, 1)?;
+
+        match args[0] {
+          Value::Integer(num) => num.checked_abs()
+            .map(Value::Integer)
+            .ok_or_else(|| KonekoError::runtime(ErrorKind::Overflow, format!("Integer overflow in abs {}", num))),
+          _ => Ok(Value::Float(Self::float_from_value(&args[0])?.abs())),
+        }
+      }
+      "floor" | "ceil" => {
+        Self::expect_n_args(&args, 1)?;
 
-            Ok(Value::Nil)
+        match args[0] {
+          Value::Integer(num) => Ok(Value::Integer(num)),
+          _ => {
+            let value = Self::float_from_value(&args[0])?;
+            let value = if name == "floor" { value.floor() } else { value.ceil() };
+            Ok(Value::Integer(value as i64))
           }
-          "next" => {
-            Self::expect_n_args(&args, 1)?;
+        }
+      }
+      "sgn" => {
+        Self::expect_n_args(&args, 1)?;
 
-            let name = match &args[0] {
-              Node::VarGet(name) => name,
-              _ => return Err(format!("Expected variable name, got {:?}", args[0]))
-            };
+        let value = Self::float_from_value(&args[0])?;
+        Ok(Value::Integer(if value > 0.0 { 1 } else if value < 0.0 { -1 } else { 0 }))
+      }
+      "min" | "max" => {
+        if args.len() < 2 {
+          return Err(KonekoError::runtime(ErrorKind::ArgumentCount, format!("Expected at least 2 arguments, got {}", args.len())));
+        }
 
-            if let Some((line_no, end, step)) = self.basic.for_stack.pop() {
-              let mut value = self.basic.vars.get(name).unwrap().clone();
-              match value {
-                Value::Integer(ref mut num) => {
-                  *num += step.to_integer()?;
-                }
-                Value::Float(ref mut num) => {
-                  *num += step.to_float()?;
-                }
-                _ => return Err(format!("Expected integer or float, got {:?}", value))
-              }
-
-              let step_sign = step.to_float()?.signum();
-
-              if value.comparison_value()? * step_sign < end.comparison_value()? {
-                self.basic.vars.insert(name.clone(), value);
-                self.basic.line_no = line_no;
-                self.basic.for_stack.push((line_no, end, step));
-                Ok(Value::Nil)
-              } else {
-                self.basic.vars.remove(name);
-                Ok(Value::Nil)
-              }
-            } else {
-              Err("Cannot next; for stack is empty!".to_string())
-            }
+        if let Some(nums) = Self::integers_from_values(&args) {
+          let num = if name == "min" { nums.into_iter().min() } else { nums.into_iter().max() };
+          return Ok(Value::Integer(num.unwrap()));
+        }
+
+        let mut result = Self::float_from_value(&args[0])?;
+        for arg in &args[1..] {
+          let value = Self::float_from_value(arg)?;
+          result = if name == "min" { result.min(value) } else { result.max(value) };
+        }
+        Ok(Value::Float(result))
+      }
+      "clamp" => {
+        // clamp value low high
+        Self::expect_n_args(&args, 3)?;
+
+        if let Some(nums) = Self::integers_from_values(&args) {
+          if nums[1] > nums[2] {
+            let message = format!("Expected low {} to be at most high {}", nums[1], nums[2]);
+            return Err(KonekoError::runtime(ErrorKind::InvalidArgument, message));
           }
-          "cls" => {
-            if args.len() > 1 {
-              return Err(format!("Expected 0 or 1 arguments, got {}", args.len()));
-            }
+          return Ok(Value::Integer(nums[0].clamp(nums[1], nums[2])));
+        }
 
-            let color = if let Some(arg) = args.get(0) {
-              Self::palette_idx_from_value(&self.interpret(arg.clone())?)?
-            } else {
-              0u8
-            };
+        let value = Self::float_from_value(&args[0])?;
+        let low = Self::float_from_value(&args[1])?;
+        let high = Self::float_from_value(&args[2])?;
+        if low > high {
+          return Err(KonekoError::runtime(ErrorKind::InvalidArgument, format!("Expected low {} to be at most high {}", low, high)));
+        }
+        Ok(Value::Float(value.max(low).min(high)))
+      }
+      "lerp" => {
+        // lerp a b t, a + (b - a) * t
+        Self::expect_n_args(&args, 3)?;
+
+        let [a, b, t] = <[Value; 3]>::try_from(args).unwrap();
+        let delta = Self::binary_op(&Token::Sub, b, a.clone())?;
+        let delta = Self::binary_op(&Token::Mul, delta, t)?;
+        Self::binary_op(&Token::Add, a, delta)
+      }
+      "exp" => {
+        Self::expect_n_args(&args, 1)?;
 
-            self.cls(color);
-            Ok(Value::Nil)
-          }
-          "loop" => {
-            Self::expect_n_args(&args, 0)?;
+        Ok(Value::Float(Self::float_from_value(&args[0])?.exp()))
+      }
+      "log" => {
+        Self::expect_n_args(&args, 1)?;
 
-            if self.basic.while_stack.len() == 0 {
-              return Err("Cannot loop; while stack is empty!".to_string());
-            }
+        let value = Self::float_from_value(&args[0])?;
+        if value <= 0.0 {
+          let message = format!("Cannot take the logarithm of non-positive number {}", value);
+          return Err(KonekoError::runtime(ErrorKind::InvalidArgument, message));
+        }
+        Ok(Value::Float(value.ln()))
+      }
+      "band" | "bor" | "bxor" => {
+        Self::expect_n_args(&args, 2)?;
+
+        let left = Self::integer_from_value(&args[0])?;
+        let right = Self::integer_from_value(&args[1])?;
+        Ok(Value::Integer(match name {
+          "band" => left & right,
+          "bor" => left | right,
+          _ => left ^ right,
+        }))
+      }
+      "bnot" => {
+        Self::expect_n_args(&args, 1)?;
 
-            let line_no = self.basic.while_stack.last().unwrap().clone();
-            let cond = match self.basic.program[line_no].node.clone() {
-              Node::BuiltinCommand { name, args, } => {
-                if name != "while" {
-                  return Err(format!("Expected while statement, got {:?}", self.basic.program[line_no].node.clone()));
-                }
+        Ok(Value::Integer(!Self::integer_from_value(&args[0])?))
+      }
+      "shl" | "shr" => {
+        // shifting by 64 or more moves every bit out, shr keeps the sign
+        Self::expect_n_args(&args, 2)?;
+
+        let value = Self::integer_from_value(&args[0])?;
+        let amount = Self::integer_from_value(&args[1])?;
+        if amount < 0 {
+          return Err(KonekoError::runtime(ErrorKind::InvalidArgument, format!("Cannot shift by negative amount {}", amount)));
+        }
 
-                if args.len() != 1 {
-                  return Err(format!("Expected 1 argument, got {}", args.len()));
-                }
+        Ok(Value::Integer(match (name, amount) {
+          ("shl", 64..) => 0,
+          ("shl", _) => value << amount,
+          (_, 64..) => value >> 63,
+          _ => value >> amount,
+        }))
+      }
+      "time" => {
+        Self::expect_n_args(&args, 0)?;
 
-                self.interpret(args[0].clone()).unwrap()
-              }
-              _ => return Err(format!("Expected if statement, got {:?}", self.basic.program[line_no].node.clone()))
-            };
+        let a = secs_since_start();
+        Ok(Value::Float(a))
+      }
+      "print" => {
+        Self::expect_n_args(&args, 1)?;
 
-            if cond.is_truthy() {
-              self.basic.line_no = line_no;
-              Ok(Value::Nil)
-            } else {
-              self.basic.while_stack.pop();
-              Ok(Value::Nil)
-            }
-          }
-          "while" => {
-            Self::expect_n_args(&args, 1)?;
+        self.print(args[0].to_string(true));
+        Ok(Value::Nil)
+      }
+      "str" => {
+        Self::expect_n_args(&args, 1)?;
 
-            let cond = self.interpret(args[0].clone())?;
-            if cond.is_truthy() {
-              self.basic.while_stack.push(self.basic.line_no);
-              Ok(Value::Nil)
-            } else {
-              let mut depth = 0;
-              loop {
-                if self.basic.program.len() <= self.basic.line_no + 1 {
-                  return Err("Unexpected end of program!".to_string());
-                }
-
-                if let Node::BuiltinCommand { name, args: _args } = self.basic.program[self.basic.line_no + 1].node.clone() {
-                  if name == "loop" {
-                    if depth == 0 {
-                      self.basic.line_no += 1;
-                      break;
-                    } else {
-                      depth -= 1;
-                    }
-                  }
-
-                  if name == "while" {
-                    depth += 1;
-                  }
-                }
-                self.basic.line_no += 1;
-              }
-              Ok(Value::Nil)
-            }
-          }
-          "goto" => {
-            Self::expect_n_args(&args, 1)?;
-
-            let orig_line_no = self.interpret(args[0].clone())?.to_integer()?;
-            let line_no =
-              self.basic.program
-                .iter()
-                .position(|x| x.line_no == orig_line_no as usize)
-                .ok_or(format!("Goto: Could not find line {}", orig_line_no))?;
-
-            self.basic.line_no = line_no;
-            self.basic.no_increment_instr_counter = true;
-            Ok(Value::Nil)
-          }
-          "ret" => {
-            Self::expect_n_args(&args, 0)?;
+        Ok(Value::String(args[0].to_string(false)))
+      }
+      "chr" => {
+        Self::expect_n_args(&args, 1)?;
 
-            if let Some(line_no) = self.basic.call_stack.pop() {
-              self.basic.line_no = line_no;
-              Ok(Value::Nil)
-            } else {
-              Err("Cannot return; callstack is empty!".to_string())
+        match args[0] {
+          Value::Integer(num) => {
+            if num < 0 || num > 255 {
+              return Err(KonekoError::runtime(ErrorKind::InvalidArgument, format!("Expected integer between 0 and 255, got {}", num)));
             }
+            Ok(Value::String((num as u8 as char).to_string()))
           }
-          "dot" => {
-            Self::expect_n_args(&args, 3)?;
+          _ => Err(KonekoError::runtime(ErrorKind::TypeMismatch, format!("Expected integer, got {:?}", args[0])))
+        }
+      }
+      "int" => {
+        Self::expect_n_args(&args, 1)?;
 
-            let x = self.interpret(args[0].clone())?.to_integer()? as i32;
-            let y = self.interpret(args[1].clone())?.to_integer()? as i32;
+        Ok(Value::Integer(args[0].to_integer_raw()?))
+      }
+      "len" => {
+        Self::expect_n_args(&args, 1)?;
 
-            let color = Self::palette_idx_from_value(&self.interpret(args[2].clone())?)?;
+        match &args[0] {
+          Value::Array(array) => Ok(Value::Integer(array.len() as i64)),
+          Value::Map(map) => Ok(Value::Integer(map.len() as i64)),
+          value => Ok(Value::Integer(Self::string_from_value(value)?.chars().count() as i64)),
+        }
+      }
+      "keys" => {
+        // the keys of a map in order, integers before strings
+        Self::expect_n_args(&args, 1)?;
 
-            self.pixel(x, y, color);
-            Ok(Value::Nil)
-          }
-          "rad" => {
-            Self::expect_n_args(&args, 1)?;
+        let Value::Map(map) = &args[0] else {
+          return Err(KonekoError::runtime(ErrorKind::TypeMismatch, format!("Expected map, got {:?}", args[0])));
+        };
+        Ok(Value::Array(map.keys().map(Key::to_value).collect()))
+      }
+      "has" => {
+        Self::expect_n_args(&args, 2)?;
 
-            let value = self.interpret(args[0].clone())?.to_float()?;
-            Ok(Value::Float(value.to_radians()))
-          }
-          "deg" => {
-            Self::expect_n_args(&args, 1)?;
+        let Value::Map(map) = &args[0] else {
+          return Err(KonekoError::runtime(ErrorKind::TypeMismatch, format!("Expected map, got {:?}", args[0])));
+        };
+        Ok(Value::Integer(map.contains_key(&Key::from_value(&args[1])?) as i64))
+      }
+      "slice" => {
+        // slice array start [end], up to but not including end
+        Self::expect_args_between(&args, 2, 3)?;
 
-            let value = self.interpret(args[0].clone())?.to_float()?;
-            Ok(Value::Float(value.to_degrees()))
-          }
-          "save" => {
-            Self::expect_n_args(&args, 1)?;
+        let Value::Array(array) = &args[0] else {
+          return Err(KonekoError::runtime(ErrorKind::TypeMismatch, format!("Expected array, got {:?}", args[0])));
+        };
+        let end = match args.get(2) {
+          Some(end) => Self::count_from_value(end)?.min(array.len()),
+          None => array.len(),
+        };
+        let start = Self::count_from_value(&args[1])?.min(end);
 
-            let filename = self.interpret(args[0].clone())?.to_string(false);
+        Ok(Value::Array(array[start..end].to_vec()))
+      }
+      "find" => {
+        // the index of the first element equal to value, or -1
+        Self::expect_n_args(&args, 2)?;
 
-            let file = File::create(Path::new(&filename));
-            if let Err(err) = file {
-              return Err(format!("Could not create file {}: {}", filename, err));
-            }
+        let Value::Array(array) = &args[0] else {
+          return Err(KonekoError::runtime(ErrorKind::TypeMismatch, format!("Expected array, got {:?}", args[0])));
+        };
 
-            let mut file = file.unwrap();
-            for line in &self.basic.program {
-              if let Err(err) = writeln!(file, "{}", line.contents) {
-                return Err(format!("Could not write to file {}: {}", &filename, &err));
-              }
-            }
+        match array.iter().position(|it| *it == args[1]) {
+          Some(index) => Ok(Value::Integer(index as i64)),
+          None => Ok(Value::Integer(-1)),
+        }
+      }
+      "concat" => {
+        if args.len() < 2 {
+          return Err(KonekoError::runtime(ErrorKind::ArgumentCount, format!("Expected at least 2 arguments, got {}", args.len())));
+        }
 
-            Ok(Value::Nil)
+        let mut result = Vec::<Value>::new();
+        for arg in args {
+          match arg {
+            Value::Array(array) => result.extend(array),
+            value => return Err(KonekoError::runtime(ErrorKind::TypeMismatch, format!("Expected array, got {:?}", value))),
           }
-          "load" => {
-            if args.len() != 1 {
-              return Err(format!("Expected 1 argument, got {}", args.len()));
-            }
-
-            let filename = match self.interpret(args[0].clone())? {
-              Value::String(str) => str,
-              _ => return Err(format!("Expected string, got {:?}", self.interpret(args[0].clone())?))
-            };
-
-            let file = File::open(Path::new(&filename));
-
-            if let Err(err) = file {
-              return Err(format!("Could not open file {}: {}", filename, err));
-            }
-
-            let mut file = file.unwrap();
-            let mut buffer = String::new();
+        }
+        Ok(Value::Array(result))
+      }
+      "mid$" => {
+        // mid$ string start [count], counting from 0
+        Self::expect_args_between(&args, 2, 3)?;
+
+        let string = Self::string_from_value(&args[0])?;
+        let start = Self::count_from_value(&args[1])?;
+        let count = match args.get(2) {
+          Some(count) => Self::count_from_value(count)?,
+          None => usize::MAX,
+        };
 
-            if let Err(err) = file.read_to_string(&mut buffer) {
-              return Err(format!("Could not read from file {}: {}", filename, err));
-            }
+        Ok(Value::String(string.chars().skip(start).take(count).collect()))
+      }
+      "left$" => {
+        Self::expect_n_args(&args, 2)?;
 
-            let program_vec = buffer.split("\n").map(|x| x.to_string()).collect::<Vec<String>>();
-            self.basic.program.clear();
+        let string = Self::string_from_value(&args[0])?;
+        let count = Self::count_from_value(&args[1])?;
 
-            for line in program_vec {
-              if line.len() == 0 {
-                continue;
-              }
+        Ok(Value::String(string.chars().take(count).collect()))
+      }
+      "right$" => {
+        Self::expect_n_args(&args, 2)?;
 
-              self.basic.add_line(line)?;
-            }
+        let string = Self::string_from_value(&args[0])?;
+        let count = Self::count_from_value(&args[1])?;
+        let skip = string.chars().count().saturating_sub(count);
 
-            Ok(Value::Nil)
-          }
-          "new" => {
-            Self::expect_n_args(&args, 0)?;
+        Ok(Value::String(string.chars().skip(skip).collect()))
+      }
+      "instr" => {
+        // instr string search [start], giving the index of the first match or -1
+        Self::expect_args_between(&args, 2, 3)?;
+
+        let string = Self::string_from_value(&args[0])?;
+        let search = Self::string_from_value(&args[1])?;
+        let start = match args.get(2) {
+          Some(start) => Self::count_from_value(start)?,
+          None => 0,
+        };
 
-            self.basic.program.clear();
-            self.basic.reset_program_state();
-            Ok(Value::Nil)
-          }
-          "text" => {
-            // text "hello!" x y color shadow background
-            if args.len() < 4 {
-              return Err(format!("Expected at least 4 arguments, got {}", args.len()));
-            }
+        let rest = string.chars().skip(start).collect::<String>();
+        match rest.find(search) {
+          Some(offset) => Ok(Value::Integer((start + rest[..offset].chars().count()) as i64)),
+          None => Ok(Value::Integer(-1)),
+        }
+      }
+      "upper$" => {
+        Self::expect_n_args(&args, 1)?;
 
-            let text = self.interpret(args[0].clone())?.to_string(false);
-            let x = self.interpret(args[1].clone())?.to_integer()? as i32;
-            let y = self.interpret(args[2].clone())?.to_integer()? as i32;
-            let color = Self::palette_idx_from_value(&self.interpret(args[3].clone())?)?;
-            let shadow = if args.len() > 4 {
-              Some(self.interpret(args[4].clone())?.to_integer()? as u8)
-            } else {
-              None
-            };
+        Ok(Value::String(Self::string_from_value(&args[0])?.to_uppercase()))
+      }
+      "lower$" => {
+        Self::expect_n_args(&args, 1)?;
 
-            let background = if args.len() > 5 {
-              Some(self.interpret(args[5].clone())?.to_integer()? as u8)
-            } else {
-              None
-            };
+        Ok(Value::String(Self::string_from_value(&args[0])?.to_lowercase()))
+      }
+      "trim$" => {
+        Self::expect_n_args(&args, 1)?;
 
-            self.text(&*text, x, y, color, shadow, background);
-            Ok(Value::Nil)
-          }
-          "inkey$" => {
-            Self::expect_n_args(&args, 0)?;
+        Ok(Value::String(Self::string_from_value(&args[0])?.trim().to_string()))
+      }
+      "replace$" => {
+        Self::expect_n_args(&args, 3)?;
+
+        let string = Self::string_from_value(&args[0])?;
+        let from = Self::string_from_value(&args[1])?;
+        let to = Self::string_from_value(&args[2])?;
+        if from.is_empty() {
+          return Err(KonekoError::runtime(ErrorKind::InvalidArgument, "Cannot replace an empty string".to_string()));
+        }
 
-            let key = if let Some(keycode) = self.keys_down.get(self.keys_idx) {
-              keycode.name()
-            } else {
-              return Ok(Value::Nil);
-            };
-            self.keys_idx += 1;
+        Ok(Value::String(string.replace(from, to)))
+      }
+      "asc" => {
+        Self::expect_n_args(&args, 1)?;
 
-            Ok(Value::String(key))
-          }
-          _ => {
-            return Err(format!("Unknown builtin command {}", name));
-          }
+        match Self::string_from_value(&args[0])?.chars().next() {
+          Some(char) => Ok(Value::Integer(char as i64)),
+          None => Err(KonekoError::runtime(ErrorKind::InvalidArgument, "Expected non-empty string, got \"\"".to_string())),
         }
       }
-      Node::End => {
-        self.basic.line_no = self.basic.program.len();
-        Ok(Value::Nil)
+      "val" => {
+        Self::expect_n_args(&args, 1)?;
+
+        let string = Self::string_from_value(&args[0])?.trim();
+        if let Ok(num) = string.parse::<i64>() {
+          Ok(Value::Integer(num))
+        } else if let Ok(num) = string.parse::<f64>() {
+          Ok(Value::Float(num))
+        } else {
+          Err(KonekoError::runtime(ErrorKind::InvalidArgument, format!("Cannot convert {:?} to a number", string)))
+        }
       }
-      Node::Nil => {
+      "split" => {
+        // an empty separator splits the string into characters
+        Self::expect_n_args(&args, 2)?;
+
+        let string = Self::string_from_value(&args[0])?;
+        let separator = Self::string_from_value(&args[1])?;
+        let parts = if separator.is_empty() {
+          string.chars().map(|char| Value::String(char.to_string())).collect()
+        } else {
+          string.split(separator).map(|part| Value::String(part.to_string())).collect()
+        };
+
+        Ok(Value::Array(parts))
+      }
+      "join" => {
+        Self::expect_n_args(&args, 2)?;
+
+        let Value::Array(elements) = &args[0] else {
+          return Err(KonekoError::runtime(ErrorKind::TypeMismatch, format!("Expected array, got {:?}", args[0])));
+        };
+        let separator = Self::string_from_value(&args[1])?;
+
+        Ok(Value::String(elements.iter().map(|it| it.to_string(false)).collect::<Vec<_>>().join(separator)))
+      }
+      "poly" => {
+        if args.len() < 2 {
+          return Err(KonekoError::runtime(ErrorKind::ArgumentCount, format!("Expected at least 2 arguments, got {}", args.len())));
+        }
+
+        if let Value::Array(elements) = &args[0] {
+          if elements.len() > 2 && args.len() == 2 {
+            let mut points = Vec::<(i32, i32)>::new();
+            for element in elements {
+              let point = Self::vec2i_from_value(element)?;
+              points.push(point);
+            }
+            let color = Self::palette_idx_from_value(&args[1])?;
+
+            self.poly(points, color)?;
+            return Ok(Value::Nil);
+          }
+        }
+
+        if args.len() < 4 {
+          return Err(KonekoError::runtime(ErrorKind::ArgumentCount, format!("Expected at least 4 arguments, got {}", args.len())));
+        }
+
+        let mut points = Vec::<(i32, i32)>::new();
+        for arg in &args[..args.len() - 1] {
+          let point = Self::vec2i_from_value(arg)?;
+          points.push(point);
+        }
+
+        let color = Self::palette_idx_from_value(&args[args.len() - 1])?;
+
+        self.poly(points, color)?;
         Ok(Value::Nil)
       }
-      Node::Array(elements) => {
-        let mut array = Vec::new();
-        for element in elements {
-          array.push(self.interpret(element)?);
+      "rim" => {
+        if args.len() < 2 {
+          return Err(KonekoError::runtime(ErrorKind::ArgumentCount, format!("Expected at least 2 arguments, got {}", args.len())));
+        }
+
+        if let Value::Array(elements) = &args[0] {
+          if elements.len() > 2 && args.len() == 2 {
+            let mut points = Vec::<(i32, i32)>::new();
+            for element in elements {
+              let point = Self::vec2i_from_value(element)?;
+              points.push(point);
+            }
+            let color = Self::palette_idx_from_value(&args[1])?;
+
+            self.outline(points, color)?;
+            return Ok(Value::Nil);
+          }
+        }
+
+        if args.len() < 4 {
+          return Err(KonekoError::runtime(ErrorKind::ArgumentCount, format!("Expected at least 4 arguments, got {}", args.len())));
         }
-        Ok(Value::Array(array))
+
+        let mut points = Vec::<(i32, i32)>::new();
+        for arg in &args[..args.len() - 1] {
+          let point = Self::vec2i_from_value(arg)?;
+          points.push(point);
+        }
+
+        let color = Self::palette_idx_from_value(&args[args.len() - 1])?;
+
+        self.outline(points, color)?;
+        Ok(Value::Nil)
       }
-      Node::IndexGet { name, index } => {
-        let index = self.interpret(*index)?;
-        let index = match index {
-          Value::Integer(num) => num as usize,
-          _ => return Err(format!("Expected integer, got {:?}", index))
-        };
+      "line" => {
+        Self::expect_n_args(&args, 3)?;
+
+        let x1y1 = Self::vec2i_from_value(&args[0])?;
+        let x2y2 = Self::vec2i_from_value(&args[1])?;
+        let color = Self::palette_idx_from_value(&args[2])?;
+
+        self.line(x1y1, x2y2, color);
+
+        Ok(Value::Nil)
+      }
+      "cls" => {
+        if args.len() > 1 {
+          return Err(KonekoError::runtime(ErrorKind::ArgumentCount, format!("Expected 0 or 1 arguments, got {}", args.len())));
+        }
 
-        let array = match self.basic.vars.get(name.as_str()) {
-          Some(Value::Array(array)) => array,
-          _ => return Err(format!("Expected array, got {:?}", self.basic.vars.get(name.as_str())))
+        let color = if let Some(arg) = args.first() {
+          Self::palette_idx_from_value(arg)?
+        } else {
+          0u8
         };
 
-        if index >= array.len() {
-          return Err(format!("Index {} out of bounds for array of length {}", index, array.len()));
+        self.cls(color);
+        Ok(Value::Nil)
+      }
+      "dot" => {
+        Self::expect_n_args(&args, 3)?;
+
+        let x = args[0].to_integer()? as i32;
+        let y = args[1].to_integer()? as i32;
+
+        let color = Self::palette_idx_from_value(&args[2])?;
+
+        self.pixel(x, y, color);
+        Ok(Value::Nil)
+      }
+      "rad" => {
+        Self::expect_n_args(&args, 1)?;
+
+        let value = args[0].to_float()?;
+        Ok(Value::Float(value.to_radians()))
+      }
+      "deg" => {
+        Self::expect_n_args(&args, 1)?;
+
+        let value = args[0].to_float()?;
+        Ok(Value::Float(value.to_degrees()))
+      }
+      "save" => {
+        Self::expect_n_args(&args, 1)?;
+
+        let filename = args[0].to_string(false);
+
+        let file = File::create(Path::new(&filename));
+        if let Err(err) = file {
+          return Err(KonekoError::runtime(ErrorKind::Io, format!("Could not create file {}: {}", filename, err)));
+        }
+
+        let mut file = file.unwrap();
+        for line in &self.basic.program {
+          if let Err(err) = writeln!(file, "{}", line.contents) {
+            return Err(KonekoError::runtime(ErrorKind::Io, format!("Could not write to file {}: {}", &filename, &err)));
+          }
         }
 
-        Ok(array[index].clone())
+        Ok(Value::Nil)
       }
-      Node::IndexSet { name, index, value } => {
-        let index = self.interpret(*index)?.to_integer()? as usize;
-        let value = self.interpret(*value)?;
+      "load" => {
+        if args.len() != 1 {
+          return Err(KonekoError::runtime(ErrorKind::ArgumentCount, format!("Expected 1 argument, got {}", args.len())));
+        }
 
-        let array = match self.basic.vars.get_mut(name.as_str()) {
-          Some(Value::Array(array)) => array,
-          _ => return Err(format!("Expected array, got {:?}", self.basic.vars.get(name.as_str())))
+        let filename = match &args[0] {
+          Value::String(str) => str.clone(),
+          value => return Err(KonekoError::runtime(ErrorKind::TypeMismatch, format!("Expected string, got {:?}", value)))
         };
 
-        if index >= array.len() {
-          return Err(format!("Index {} out of bounds for array of length {}", index, array.len()));
+        let file = File::open(Path::new(&filename));
+
+        if let Err(err) = file {
+          return Err(KonekoError::runtime(ErrorKind::Io, format!("Could not open file {}: {}", filename, err)));
+        }
+
+        let mut file = file.unwrap();
+        let mut buffer = String::new();
+
+        if let Err(err) = file.read_to_string(&mut buffer) {
+          return Err(KonekoError::runtime(ErrorKind::Io, format!("Could not read from file {}: {}", filename, err)));
+        }
+
+        // the bad lines are listed on the BASIC screen, like the checker's findings
+        if let Err(errors) = self.basic.load_program(&buffer) {
+          self.findings = errors.iter().map(|it| it.to_string()).collect();
+          let message = format!("Could not load {}: {} bad lines, kept the old program", filename, errors.len());
+          return Err(KonekoError::runtime(ErrorKind::Syntax, message));
         }
 
-        array[index] = value;
         Ok(Value::Nil)
       }
-      Node::EmptyArray(size) => {
-        let size = self.interpret(*size)?.to_integer()? as usize;
-        Ok(Value::Array(vec![Value::Nil; size]))
+      "new" => {
+        Self::expect_n_args(&args, 0)?;
+
+        self.basic.clear_program();
+        self.basic.reset_program_state();
+        Ok(Value::Nil)
+      }
+      "text" => {
+        // text "hello!" x y color shadow background
+        if args.len() < 4 {
+          return Err(KonekoError::runtime(ErrorKind::ArgumentCount, format!("Expected at least 4 arguments, got {}", args.len())));
+        }
+
+        let text = args[0].to_string(false);
+        let x = args[1].to_integer()? as i32;
+        let y = args[2].to_integer()? as i32;
+        let color = Self::palette_idx_from_value(&args[3])?;
+        let shadow = if args.len() > 4 {
+          Some(Self::palette_idx_from_value(&args[4])?)
+        } else {
+          None
+        };
+
+        let background = if args.len() > 5 {
+          Some(Self::palette_idx_from_value(&args[5])?)
+        } else {
+          None
+        };
+
+        self.text(&text, x, y, color, shadow, background);
+        Ok(Value::Nil)
+      }
+      "check" => {
+        Self::expect_n_args(&args, 0)?;
+
+        self.check();
+        Ok(Value::Integer(self.findings.len() as i64))
+      }
+      "inkey$" => {
+        Self::expect_n_args(&args, 0)?;
+
+        let key = if let Some(keycode) = self.keys_down.get(self.keys_idx) {
+          keycode.name()
+        } else {
+          return Ok(Value::Nil);
+        };
+        self.keys_idx += 1;
+
+        Ok(Value::String(key))
+      }
+      _ => {
+        Err(KonekoError::runtime(ErrorKind::UndefinedFunction, format!("Unknown builtin command {}", name)))
+      }
+    }
+  }
+
+  fn exec_op(&mut self, op: &Op) -> Result<(), KonekoError> {
+    match op {
+      Op::Push(value) => {
+        self.basic.stack.push(value.clone());
+      }
+      Op::Pop => {
+        self.basic.pop();
+      }
+      Op::Load(slot) => {
+        if let Some(value) = self.basic.get_var(*slot) {
+          self.basic.stack.push(value.clone());
+        } else {
+          return Err(KonekoError::runtime(ErrorKind::UndefinedVariable, format!("Variable {} not found!", self.basic.slots.name(*slot))));
+        }
+      }
+      Op::Store(slot) => {
+        let value = self.basic.stack.last().cloned().unwrap_or(Value::Nil);
+        self.basic.set_var(*slot, value);
+      }
+      Op::IndexGet(slot, n) => {
+        let indices = self.basic.stack.split_off(self.basic.stack.len() - n);
+        let Some(array) = self.basic.get_var(*slot) else {
+          return Err(KonekoError::runtime(ErrorKind::UndefinedVariable, format!("Variable {} not found!", self.basic.slots.name(*slot))));
+        };
+
+        let value = Self::element(array, &indices)?.clone();
+        self.basic.stack.push(value);
+      }
+      Op::IndexSet(slot, n) => {
+        let value = self.basic.pop();
+        let indices = self.basic.stack.split_off(self.basic.stack.len() - n);
+        let Some(array) = self.basic.get_var_mut(*slot) else {
+          return Err(KonekoError::runtime(ErrorKind::UndefinedVariable, format!("Variable {} not found!", self.basic.slots.name(*slot))));
+        };
+
+        // the last index has to land inside an array, map or record, not just on a value, and
+        // may add a new key to a map
+        let (last, path) = indices.split_last().unwrap();
+        match Self::element_mut(array, path)? {
+          Value::Array(array) => {
+            let index = Self::array_index(array, last)?;
+            array[index] = value;
+          }
+          Value::Map(map) => {
+            map.insert(Key::from_value(last)?, value);
+          }
+          Value::Record(record, values) => {
+            values[record.field(last)?] = value;
+          }
+          value => return Err(KonekoError::runtime(ErrorKind::TypeMismatch, format!("Expected array, map or record, got {:?}", value)))
+        }
+        self.basic.stack.push(Value::Nil);
+      }
+      Op::Index => {
+        let index = self.basic.pop();
+        let array = self.basic.pop();
+        let value = Self::element(&array, &[index])?.clone();
+        self.basic.stack.push(value);
+      }
+      Op::MakeArray(len) => {
+        let array = self.basic.stack.split_off(self.basic.stack.len() - len);
+        self.basic.stack.push(Value::Array(array));
+      }
+      Op::MakeMap(len) => {
+        let entries = self.basic.stack.split_off(self.basic.stack.len() - 2 * len);
+        let mut map = BTreeMap::new();
+        for pair in entries.chunks(2) {
+          map.insert(Key::from_value(&pair[0])?, pair[1].clone());
+        }
+        self.basic.stack.push(Value::Map(map));
+      }
+      Op::MakeRecord(record, len) => {
+        let mut values = self.basic.stack.split_off(self.basic.stack.len() - len);
+        values.resize(record.fields.len(), Value::Nil);
+        self.basic.stack.push(Value::Record(record.clone(), values));
+      }
+      Op::EmptyArray(n) => {
+        let sizes = self.basic.stack.split_off(self.basic.stack.len() - n).iter()
+          .map(Self::count_from_value)
+          .collect::<Result<Vec<_>, _>>()?;
+        self.basic.stack.push(Self::empty_array(&sizes)?);
+      }
+      Op::BinOp(op) => {
+        let right = self.basic.pop();
+        let left = self.basic.pop();
+        let value = Self::binary_op(op, left, right)?;
+        self.basic.stack.push(value);
+      }
+      Op::UnOp(op) => {
+        let right = self.basic.pop();
+        let value = Self::unary_op(op, right)?;
+        self.basic.stack.push(value);
+      }
+      Op::Builtin(name, argc) => {
+        let args = self.basic.stack.split_off(self.basic.stack.len() - argc);
+        let value = self.builtin(name, args)?;
+        self.basic.stack.push(value);
+      }
+      Op::BuiltinInPlace(name, slot, n, argc) => {
+        let args = self.basic.stack.split_off(self.basic.stack.len() - argc);
+        let indices = self.basic.stack.split_off(self.basic.stack.len() - n);
+        let Some(value) = self.basic.get_var_mut(*slot) else {
+          return Err(KonekoError::runtime(ErrorKind::UndefinedVariable, format!("Variable {} not found!", self.basic.slots.name(*slot))));
+        };
+
+        let value = Self::builtin_in_place(name, Self::element_mut(value, &indices)?, args)?;
+        self.basic.stack.push(value);
+      }
+      Op::Call(idx, argc) => {
+        let args = self.basic.stack.split_off(self.basic.stack.len() - argc);
+        self.call_function(*idx, args)?;
+      }
+      Op::Return => {
+        let value = self.basic.pop();
+        self.return_from_function(value, "return")?;
+      }
+      Op::EndDef => {
+        self.return_from_function(Value::Nil, "end def")?;
+      }
+      Op::Local(slots) => {
+        if self.basic.frames.is_empty() {
+          return Err(KonekoError::runtime(ErrorKind::Misplaced, "Cannot declare local; not inside a function!".to_string()));
+        }
+
+        for slot in slots {
+          self.basic.declare_local(*slot);
+        }
+      }
+      Op::Jump(pc) => {
+        self.basic.pc = *pc;
+      }
+      Op::JumpIfFalse(pc) => {
+        if !self.basic.pop().is_truthy() {
+          self.basic.pc = *pc;
+        }
+      }
+      Op::For(slot) => {
+        let step = self.basic.pop();
+        let end = self.basic.pop();
+        let start = self.basic.pop();
+
+        // loop variables are local to the enclosing function call
+        self.basic.declare_local(*slot);
+
+        if let Some(_value) = self.basic.get_var(*slot) {
+          return Err(KonekoError::runtime(ErrorKind::Misplaced, format!("Variable {} already exists!", self.basic.slots.name(*slot))));
+        }
+
+        self.basic.set_var(*slot, start);
+        self.basic.for_stack.push((self.basic.pc, end, step));
+      }
+      Op::Next(slot) => {
+        if let Some((pc, end, step)) = self.basic.for_stack.pop() {
+          let Some(mut value) = self.basic.get_var(*slot).cloned() else {
+            return Err(KonekoError::runtime(ErrorKind::UndefinedVariable, format!("Variable {} not found!", self.basic.slots.name(*slot))));
+          };
+          match value {
+            Value::Integer(ref mut num) => {
+              let step = step.to_integer()?;
+              *num = num.checked_add(step)
+                .ok_or_else(|| {
+                  let message = format!("Integer overflow in next {}", self.basic.slots.name(*slot));
+                  KonekoError::runtime(ErrorKind::Overflow, message)
+                })?;
+            }
+            Value::Float(ref mut num) => {
+              *num += step.to_float()?;
+            }
+            _ => return Err(KonekoError::runtime(ErrorKind::TypeMismatch, format!("Expected integer or float, got {:?}", value)))
+          }
+
+          let step_sign = step.to_float()?.signum();
+
+          if value.comparison_value()? * step_sign < end.comparison_value()? {
+            self.basic.set_var(*slot, value);
+            self.basic.pc = pc;
+            self.basic.for_stack.push((pc, end, step));
+          } else {
+            self.basic.remove_var(*slot);
+          }
+        } else {
+          return Err(KonekoError::runtime(ErrorKind::Misplaced, "Cannot next; for stack is empty!".to_string()));
+        }
+      }
+      Op::While { cond, exit } => {
+        if self.basic.pop().is_truthy() {
+          self.basic.while_stack.push(*cond);
+        } else {
+          self.basic.pc = *exit;
+        }
+      }
+      Op::Loop => {
+        // re-evaluates the innermost while's condition
+        self.basic.pc = self.basic.while_stack.pop()
+          .ok_or_else(|| KonekoError::runtime(ErrorKind::Misplaced, "Cannot loop; while stack is empty!".to_string()))?;
+      }
+      Op::Goto => {
+        let target = self.basic.pop();
+        self.basic.pc = self.line_pc(target, "Goto")?;
+      }
+      Op::Gosub(pc) => {
+        self.basic.call_stack.push(self.basic.pc);
+        self.basic.pc = *pc;
+      }
+      Op::GosubLine => {
+        let target = self.basic.pop();
+        let pc = self.line_pc(target, "Gosub")?;
+        self.basic.call_stack.push(self.basic.pc);
+        self.basic.pc = pc;
+      }
+      Op::Ret => {
+        self.basic.pc = self.basic.call_stack.pop()
+          .ok_or_else(|| KonekoError::runtime(ErrorKind::Misplaced, "Cannot return; callstack is empty!".to_string()))?;
+        // returning from the error handler leaves it, like resume next
+        if matches!(self.basic.error_line, Some((_, depth)) if depth == self.basic.call_stack.len()) {
+          self.basic.error_line = None;
+        }
+      }
+      Op::Read => {
+        let value = self.basic.data.get(self.basic.data_pointer).cloned()
+          .ok_or_else(|| KonekoError::runtime(ErrorKind::OutOfData, "Read: Out of data".to_string()))?;
+        self.basic.data_pointer += 1;
+        self.basic.stack.push(value);
+      }
+      Op::Restore => {
+        self.basic.data_pointer = 0;
+      }
+      Op::RestoreLine => {
+        let target = self.basic.pop();
+        let line_no = target.to_integer()? as usize;
+        if !self.basic.line_pcs.contains_key(&line_no) {
+          return Err(KonekoError::runtime(ErrorKind::UndefinedTarget, format!("Restore: Could not find line {}", line_no)));
+        }
+        self.basic.data_pointer = self.basic.data_lines.range(line_no..).next()
+          .map_or(self.basic.data.len(), |(_, &idx)| idx);
+      }
+      Op::OnError(handler) => {
+        self.basic.error_handler = *handler;
+      }
+      Op::OnErrorLine => {
+        let target = self.basic.pop();
+        self.basic.error_handler = Some(self.line_pc(target, "On error")?);
+      }
+      Op::Resume(next) => {
+        let (pc, depth) = self.basic.error_line.take()
+          .ok_or_else(|| KonekoError::runtime(ErrorKind::Misplaced, "Cannot resume; no error to resume from!".to_string()))?;
+        self.basic.call_stack.truncate(depth);
+        self.basic.pc = if *next { self.basic.next_stmt(pc) } else { pc };
+      }
+      Op::Error(error) => {
+        return Err(error.clone());
+      }
+      Op::Unterminated(message) => {
+        self.basic.stmt_pc = self.basic.last_stmt();
+        return Err(KonekoError::runtime(ErrorKind::Misplaced, message.clone()));
+      }
+      Op::Stmt(..) | Op::End | Op::EndOfProgram | Op::Halt | Op::Input(_) => {
+        // handled by run, which owns the statement boundaries and suspends for input
+      }
+    }
+    Ok(())
+  }
+
+  fn line_pc(&self, target: Value, cmd: &str) -> Result<usize, KonekoError> {
+    let line_no = target.to_integer()? as usize;
+    self.basic.line_pcs.get(&line_no).copied()
+      .ok_or_else(|| KonekoError::runtime(ErrorKind::UndefinedTarget, format!("{}: Could not find line {}", cmd, line_no)))
+  }
+
+  fn call_function(&mut self, idx: usize, args: Vec<Value>) -> Result<(), KonekoError> {
+    let function = &self.basic.functions[idx];
+    if args.len() != function.params.len() {
+      let message = format!("{} expects {} arguments, got {}", function.name, function.params.len(), args.len());
+      return Err(KonekoError::runtime(ErrorKind::ArgumentCount, message));
+    }
+
+    if self.basic.frames.len() >= MAX_CALL_DEPTH {
+      return Err(KonekoError::runtime(ErrorKind::StackOverflow, format!("Stack overflow; more than {} nested calls!", MAX_CALL_DEPTH)));
+    }
+
+    let locals = function.params.iter().copied().zip(args.into_iter().map(Some)).collect();
+    let body = function.body;
+    self.basic.frames.push(Frame {
+      locals,
+      function: idx,
+      return_pc: self.basic.pc,
+      stmt_pc: self.basic.stmt_pc,
+      stack_base: self.basic.stack.len(),
+      call_depth: self.basic.call_stack.len(),
+      while_depth: self.basic.while_stack.len(),
+      for_depth: self.basic.for_stack.len(),
+    });
+    self.basic.pc = body;
+    Ok(())
+  }
+
+  fn return_from_function(&mut self, value: Value, cmd: &str) -> Result<(), KonekoError> {
+    let frame = self.basic.frames.pop()
+      .ok_or_else(|| KonekoError::runtime(ErrorKind::Misplaced, format!("Cannot {}; not inside a function!", cmd)))?;
+
+    self.basic.call_stack.truncate(frame.call_depth);
+    self.basic.while_stack.truncate(frame.while_depth);
+    self.basic.for_stack.truncate(frame.for_depth);
+    self.basic.stack.truncate(frame.stack_base);
+    self.basic.stack.push(value);
+    self.basic.pc = frame.return_pc;
+    self.basic.stmt_pc = frame.stmt_pc;
+    Ok(())
+  }
+
+  // the line and statement holding pc, or None in the immediate mode line
+  fn stmt_at(&self, pc: usize) -> Option<(usize, usize)> {
+    if pc >= self.basic.program_code_len {
+      return None;
+    }
+
+    (0..=pc).rev().find_map(|pc| match self.basic.code[pc] {
+      Op::Stmt(idx, n) => Some((idx, n)),
+      _ => None,
+    })
+  }
+
+  fn line_at(&self, pc: usize) -> Option<usize> {
+    self.stmt_at(pc).map(|(idx, _)| self.basic.program[idx].line_no)
+  }
+
+  fn span_at(&self, pc: usize) -> Option<(usize, usize)> {
+    self.stmt_at(pc).map(|(idx, n)| self.basic.program[idx].spans[n])
+  }
+
+  // fills in the line and statement the op at pc failed on, and the gosubs that led there
+  fn locate(&self, mut error: KonekoError, pc: usize) -> KonekoError {
+    if let KonekoError::Runtime { line_no, span, gosubs, .. } = &mut error {
+      *line_no = self.line_at(pc);
+      *span = self.span_at(pc);
+      *gosubs = self.basic.call_stack.iter().filter_map(|&return_pc| self.line_at(return_pc - 1)).collect();
+    }
+    error
+  }
+
+  // hands an error to the program's handler, or abandons the failing statement
+  fn trap(&mut self, error: KonekoError) -> Result<(), KonekoError> {
+    if let (Some(handler), None) = (self.basic.error_handler, self.basic.error_line) {
+      // resume picks up from the failing statement, and ret from the one after it
+      let erl = error.line_no().unwrap_or(0) as i64;
+      self.basic.set_global("err$", Value::String(error.message().to_string()));
+      self.basic.set_global("erl", Value::Integer(erl));
+      self.basic.error_line = Some((self.basic.stmt_pc, self.basic.call_stack.len()));
+      self.basic.call_stack.push(self.basic.next_stmt(self.basic.stmt_pc));
+
+      let stack_base = self.basic.frames.last().map_or(0, |frame| frame.stack_base);
+      self.basic.stack.truncate(stack_base);
+      self.basic.pc = handler;
+      return Ok(());
+    }
+
+    self.basic.unwind();
+    self.basic.pc = self.basic.next_stmt(self.basic.stmt_pc);
+    Err(error)
+  }
+
+  // runs bytecode from the current pc. the program yields at a statement once it refreshes or
+  // runs out of time, and an immediate line stops once it halts or jumps into the program
+  fn run(&mut self, immediate: bool, begin: u128) -> Result<Value, KonekoError> {
+    let code = Rc::clone(&self.basic.code);
+    loop {
+      let pc = self.basic.pc;
+      self.basic.pc += 1;
+
+      let res = match &code[pc] {
+        Op::Stmt(..) if immediate && self.basic.frames.is_empty() => {
+          // reaching a statement outside the immediate line means it jumped into the program
+          self.basic.pc = pc;
+          return Ok(Value::Nil);
+        }
+        Op::Stmt(..) if immediate && millis() - begin >= 2000 => {
+          // a function called from the prompt can't be resumed next frame, so give up on it
+          let message = "Timeout, a function called in immediate mode ran for too long".to_string();
+          Err(KonekoError::runtime(ErrorKind::Timeout, message))
+        }
+        Op::Stmt(..) if !immediate && (self.basic.refresh || millis() - begin >= 2000) => {
+          // frames are kept, so a function suspended here picks up where it left off
+          self.basic.pc = pc;
+          return Ok(Value::Nil);
+        }
+        Op::Stmt(..) => {
+          self.basic.stmt_pc = pc;
+          Ok(())
+        }
+        Op::Halt => {
+          return Ok(self.basic.pop());
+        }
+        Op::Input(_) if immediate => {
+          let message = "Input: Cannot wait for a line in immediate mode".to_string();
+          Err(KonekoError::runtime(ErrorKind::Misplaced, message))
+        }
+        Op::Input(numeric) => match self.input.take() {
+          Some(input) if input.done => {
+            self.basic.pop();
+            let line = input.line.trim();
+            let value = if !numeric {
+              Value::String(input.line)
+            } else if let Ok(num) = line.parse::<i64>() {
+              Value::Integer(num)
+            } else if let Some(num) = line.parse::<f64>().ok().filter(|num| num.is_finite()) {
+              Value::Float(num)
+            } else {
+              Value::String(input.line)
+            };
+            self.basic.stack.push(value);
+            Ok(())
+          }
+          input => {
+            // comes back to this op every frame until Return is pressed, so the timeout never runs out
+            match input {
+              Some(input) => self.input = Some(input),
+              None => self.start_input(self.basic.stack.last().map_or(String::new(), |it| it.to_string(false))),
+            }
+            self.basic.pc = pc;
+            return Ok(Value::Nil);
+          }
+        },
+        Op::End => {
+          self.basic.frames.clear();
+          self.basic.call_stack.clear();
+          self.basic.while_stack.clear();
+          self.basic.for_stack.clear();
+          self.basic.stack.clear();
+          self.basic.pc = self.basic.program_end;
+          return Ok(Value::Nil);
+        }
+        Op::EndOfProgram => match self.basic.frames.last() {
+          None => {
+            self.basic.pc = self.basic.program_end;
+            return Ok(Value::Nil);
+          }
+          Some(frame) => {
+            let message = format!("{}: Could not find matching end def", self.basic.functions[frame.function].name);
+            Err(KonekoError::runtime(ErrorKind::Misplaced, message))
+          }
+        },
+        op => self.exec_op(op),
+      };
+
+      if !Rc::ptr_eq(&code, &self.basic.code) {
+        // load or new replaced the program being run, so there's no line left to blame
+        self.basic.unwind();
+        self.basic.pc = self.basic.program_end;
+        return res.map(|_| Value::Nil);
+      }
+
+      if let Err(error) = res {
+        let error = self.locate(error, pc);
+        if immediate {
+          self.basic.unwind();
+          return Err(error);
+        }
+        self.trap(error)?;
       }
     }
   }
 
-  pub fn exec_current_line(&mut self) -> Result<Value, String> {
-    if self.basic.line_no >= self.basic.program.len() {
-      return Err("Program buffer empty!".to_string());
+  pub fn run_program(&mut self, begin: u128) -> Result<(), KonekoError> {
+    self.run(false, begin)?;
+    Ok(())
+  }
+
+  // runs the statements of an immediate mode line, giving the value of the last one
+  pub fn run_immediate(&mut self, nodes: Vec<Node>) -> Result<Value, KonekoError> {
+    self.basic.pc = self.basic.compile_immediate(&nodes);
+    self.run(true, millis())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::koneko::{BASIC_SCREEN, EXEC_SCREEN, Koneko, millis};
+  use crate::koneko_error::{ErrorKind, KonekoError};
+  use crate::lex_parse_basic::{Token, Value};
+  use crate::palette;
+
+  fn koneko() -> Koneko {
+    Koneko::new(palette::sweetie_16(), "font.png")
+  }
+
+  fn run_line(ko: &mut Koneko, line: &str) -> Result<Value, KonekoError> {
+    let nodes = ko.basic.add_line(line.to_string())?.expect("not an immediate mode line");
+    ko.run_immediate(nodes)
+  }
+
+  fn run_program(ko: &mut Koneko, source: &str) -> Result<(), KonekoError> {
+    ko.basic.load_program(source).expect("program should load");
+    ko.basic.reset_program_state();
+    ko.run_program(millis())
+  }
+
+  fn kind<T: std::fmt::Debug>(res: Result<T, KonekoError>) -> ErrorKind {
+    res.expect_err("expected an error").kind()
+  }
+
+  // the value of an expression typed at the prompt, shown the way print shows it
+  fn eval(ko: &mut Koneko, expr: &str) -> Result<String, KonekoError> {
+    run_line(ko, &format!("x = {}", expr)).map(|value| value.to_string(true))
+  }
+
+  fn globals<const N: usize>(ko: &mut Koneko, names: [&str; N]) -> [Option<Value>; N] {
+    names.map(|name| {
+      let slot = ko.basic.slots.resolve(name);
+      ko.basic.get_var(slot).cloned()
+    })
+  }
+
+  #[test]
+  fn unclosed_paren_is_a_syntax_error() {
+    let mut ko = koneko();
+    assert_eq!(kind(ko.basic.add_line("print (1".to_string())), ErrorKind::Syntax);
+  }
+
+  #[test]
+  fn int_of_bad_string_fails() {
+    let mut ko = koneko();
+    assert_eq!(kind(run_line(&mut ko, "x = int(\"abc\")")), ErrorKind::InvalidArgument);
+  }
+
+  #[test]
+  fn float_of_bad_string_fails() {
+    let mut ko = koneko();
+    assert_eq!(kind(run_line(&mut ko, "x = rad(\"abc\")")), ErrorKind::InvalidArgument);
+  }
+
+  #[test]
+  fn integer_division_by_zero_fails() {
+    let mut ko = koneko();
+    assert_eq!(kind(run_line(&mut ko, "x = 1 / 0")), ErrorKind::DivisionByZero);
+  }
+
+  #[test]
+  fn integer_remainder_by_zero_fails() {
+    let mut ko = koneko();
+    assert_eq!(kind(run_line(&mut ko, "x = 1 % 0")), ErrorKind::DivisionByZero);
+  }
+
+  #[test]
+  fn next_on_missing_variable_fails() {
+    let mut ko = koneko();
+    let error = run_program(&mut ko, "10 for i = 1 to 3\n20 next j").unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::UndefinedVariable);
+    assert_eq!(error.line_no(), Some(20));
+  }
+
+  #[test]
+  fn while_condition_failing_on_loop_fails() {
+    let mut ko = koneko();
+    let error = run_program(&mut ko, "10 a = {1, 2}\n20 i = 0\n30 while a[i] > 0\n40 i = i + 1\n50 loop").unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::OutOfBounds);
+    assert_eq!(error.line_no(), Some(30));
+  }
+
+  #[test]
+  fn palette_index_out_of_range_fails() {
+    let mut ko = koneko();
+    assert_eq!(kind(run_line(&mut ko, "dot 0 0 300")), ErrorKind::InvalidArgument);
+    assert_eq!(kind(run_line(&mut ko, "cls 16")), ErrorKind::InvalidArgument);
+  }
+
+  #[test]
+  fn rnd_with_empty_range_fails() {
+    let mut ko = koneko();
+    assert_eq!(kind(run_line(&mut ko, "x = rnd(5, 5)")), ErrorKind::InvalidArgument);
+    assert_eq!(kind(run_line(&mut ko, "x = rnd(5, 1)")), ErrorKind::InvalidArgument);
+    assert!(matches!(run_line(&mut ko, "x = rnd(1, 5)"), Ok(Value::Float(num)) if (1.0..5.0).contains(&num)));
+  }
+
+  #[test]
+  fn looping_function_times_out() {
+    let mut ko = koneko();
+    ko.basic.load_program("10 def f()\n20 while 1\n30 loop\n40 end def\n50 f()").unwrap();
+    ko.basic.reset_program_state();
+    // returns once the 2 seconds are up instead of spinning inside f forever
+    ko.run_program(millis()).unwrap();
+    assert_eq!(ko.basic.frames.len(), 1);
+  }
+
+  #[test]
+  fn refresh_in_function_yields_and_resumes() {
+    let mut ko = koneko();
+    ko.basic.load_program("10 def f()\n20 n = n + 1\n30 refresh\n40 end def\n50 n = 0\n60 f()\n70 f()").unwrap();
+    ko.basic.reset_program_state();
+    let n = ko.basic.slots.resolve("n");
+
+    for calls in 1..=2 {
+      ko.run_program(millis()).unwrap();
+      assert_eq!(ko.basic.frames.len(), 1);
+      assert_eq!(ko.basic.get_var(n), Some(&Value::Integer(calls)));
+      ko.basic.refresh = false;
+    }
+
+    ko.run_program(millis()).unwrap();
+    assert!(ko.basic.frames.is_empty());
+    assert_eq!(ko.basic.pc, ko.basic.program_end);
+  }
+
+  #[test]
+  fn looping_function_in_immediate_mode_times_out() {
+    let mut ko = koneko();
+    ko.basic.load_program("10 def f()\n20 while 1\n30 loop\n40 end def").unwrap();
+    ko.basic.reset_program_state();
+    assert_eq!(kind(run_line(&mut ko, "f()")), ErrorKind::Timeout);
+    assert!(ko.basic.frames.is_empty());
+  }
+
+  #[test]
+  fn end_inside_function_stops_program() {
+    let mut ko = koneko();
+    let source = "10 gosub 40\n20 x = 1\n30 end\n40 while 1\n50 f()\n60 loop\n70 def f()\n80 end\n90 end def";
+    run_program(&mut ko, source).unwrap();
+    assert!(ko.basic.frames.is_empty());
+    assert!(ko.basic.call_stack.is_empty());
+    assert!(ko.basic.while_stack.is_empty());
+    assert_eq!(ko.basic.pc, ko.basic.program_end);
+    let x = ko.basic.slots.resolve("x");
+    assert_eq!(ko.basic.get_var(x), None);
+  }
+
+  #[test]
+  fn ret_from_error_handler_continues_after_failing_line() {
+    let mut ko = koneko();
+    let source = "10 on error gosub 100\n20 x = 1 / 0\n30 y = erl\n40 z = 1 / 0\n50 end\n100 ret";
+    run_program(&mut ko, source).unwrap();
+    let y = ko.basic.slots.resolve("y");
+    assert_eq!(ko.basic.get_var(y), Some(&Value::Integer(20)));
+    // the handler was left by ret, so the second error is trapped too
+    let erl = ko.basic.slots.resolve("erl");
+    assert_eq!(ko.basic.get_var(erl), Some(&Value::Integer(40)));
+    assert!(ko.basic.call_stack.is_empty());
+  }
+
+  #[test]
+  fn unassigned_variable_is_only_a_warning() {
+    let mut ko = koneko();
+    ko.basic.load_program("10 print x").unwrap();
+    assert_eq!(ko.check(), 0);
+    assert_eq!(ko.findings, vec!["Line 10: Warning: Variable x is never assigned".to_string()]);
+
+    ko.basic.load_program("10 print x\n20 goto 99").unwrap();
+    assert_eq!(ko.check(), 1);
+    assert_eq!(ko.findings.len(), 2);
+  }
+
+  #[test]
+  fn runtime_error_is_shown_on_basic_screen() {
+    let mut ko = koneko();
+    ko.basic.load_program("10 x = 1\n20 y = 2 : z = w + 1").unwrap();
+    ko.screen = EXEC_SCREEN;
+    ko.basic.reset_program_state();
+    assert_eq!(kind(ko.execute_code()), ErrorKind::UndefinedVariable);
+
+    assert_eq!(ko.screen, BASIC_SCREEN);
+    assert_eq!(ko.error.as_deref(), Some("Line 20: Variable w not found!"));
+    assert_eq!(ko.current_line, "20 y = 2 : z = w + 1");
+    assert_eq!(ko.error_at, Some((ko.current_line.clone(), (11, 20))));
+  }
+
+  #[test]
+  fn single_line_if_owns_the_rest_of_the_line() {
+    let mut ko = koneko();
+    let source = "10 x = 0\n20 if x then y = 1 : z = 2\n30 if 1 then a = 1 : b = 2 else c = 3 : d = 4";
+    run_program(&mut ko, source).unwrap();
+    assert_eq!(globals(&mut ko, ["y", "z", "a", "b", "c", "d"]), [None, None, Some(Value::Integer(1)), Some(Value::Integer(2)), None, None]);
+  }
+
+  #[test]
+  fn lexes_hex_binary_and_overflowing_literals() {
+    let ko = koneko();
+    let (tokens, error) = ko.basic.lex_line("x = &hFF + 0x10 + 0b1010 + 0xFFFFFFFFFFFFFFFF");
+    assert_eq!(error, None);
+    let nums = tokens.iter().filter_map(|(token, _, _)| match token {
+      Token::Integer(num) => Some(*num),
+      _ => None,
+    }).collect::<Vec<_>>();
+    assert_eq!(nums, vec![255, 16, 10, -1]);
+
+    let cases = [
+      ("x = 0x1FFFFFFFFFFFFFFFF", ErrorKind::Overflow, (4, 23)),
+      ("x = 99999999999999999999", ErrorKind::Overflow, (4, 24)),
+      ("x = 1e999", ErrorKind::Overflow, (4, 9)),
+      ("x = 0b102", ErrorKind::Syntax, (4, 9)),
+    ];
+    for (src, kind, span) in cases {
+      let error = ko.basic.lex_line(src).1.expect("expected a lex error");
+      assert_eq!((src, error.kind(), error.span()), (src, kind, Some(span)));
+    }
+  }
+
+  #[test]
+  fn leading_hex_literal_is_not_a_line_number() {
+    let mut ko = koneko();
+    assert_eq!(run_line(&mut ko, "&hFF + 1").unwrap(), Value::Integer(256));
+    assert_eq!(run_line(&mut ko, "0b11").unwrap(), Value::Integer(3));
+    assert!(ko.basic.program.is_empty());
+  }
+
+  #[test]
+  fn input_reads_numbers_into_variables_without_dollar() {
+    let mut ko = koneko();
+    let source = "10 input n\n20 input s$\n30 input name\n40 m = n + 1";
+    ko.basic.load_program(source).unwrap();
+    ko.basic.reset_program_state();
+    for line in ["41", "41", "koneko"] {
+      ko.run_program(millis()).unwrap();
+      let input = ko.input.as_mut().expect("waiting for input");
+      input.line = line.to_string();
+      input.done = true;
+    }
+    ko.run_program(millis()).unwrap();
+
+    assert_eq!(globals(&mut ko, ["m", "s$", "name"]), [
+      Some(Value::Integer(42)),
+      Some(Value::String("41".to_string())),
+      Some(Value::String("koneko".to_string())),
+    ]);
+  }
+
+  #[test]
+  fn duplicate_label_on_one_line_fails() {
+    let mut ko = koneko();
+    let error = ko.basic.add_line("10 @a: @a:".to_string()).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::Syntax);
+    assert_eq!((error.line_no(), error.span()), (Some(10), Some((7, 10))));
+    assert!(ko.basic.program.is_empty());
+
+    let errors = ko.basic.load_program("10 @a: x = 1 : @a:").unwrap_err();
+    assert_eq!(errors.len(), 1);
+  }
+
+  #[test]
+  fn block_if_runs_one_branch() {
+    let mut ko = koneko();
+    let source = "\
+10 s = \"\"
+20 for i = 0 to 4
+30 if i == 0 then
+40 s = s + \"a\"
+50 elseif i == 1 then
+60 s = s + \"b\"
+70 else if i == 2 then
+80 if 0 then
+90 s = s + \"x\"
+100 else
+110 s = s + \"c\"
+120 end if
+130 else
+140 s = s + \"d\"
+150 end if
+160 next i";
+    run_program(&mut ko, source).unwrap();
+    assert_eq!(globals(&mut ko, ["s"]), [Some(Value::String("abcd".to_string()))]);
+  }
+
+  #[test]
+  fn block_if_without_end_if_is_a_mismatch() {
+    let mut ko = koneko();
+    ko.basic.load_program("10 if 1 then\n20 x = 1\n30 end if\n40 end if").unwrap();
+    assert_eq!(ko.basic.mismatches, vec![(40, "End if has no matching if".to_string())]);
+    ko.basic.load_program("10 if 1 then\n20 x = 1").unwrap();
+    assert_eq!(ko.basic.mismatches, vec![(10, "If has no matching end if".to_string())]);
+  }
+
+  #[test]
+  fn goto_and_gosub_jump_to_labels() {
+    let mut ko = koneko();
+    let source = "10 gosub @sub\n20 goto @done\n30 x = 1\n40 @sub: y = 2\n50 ret\n60 @done: z = @sub";
+    run_program(&mut ko, source).unwrap();
+    assert_eq!(globals(&mut ko, ["x", "y", "z"]), [None, Some(Value::Integer(2)), Some(Value::Integer(40))]);
+
+    // renumbering around a label doesn't break jumps to it
+    ko.basic.add_line("35 x = 3".to_string()).unwrap();
+    ko.basic.add_line("40".to_string()).unwrap();
+    ko.basic.add_line("45 @sub: y = 4".to_string()).unwrap();
+    ko.basic.reset_program_state();
+    ko.run_program(millis()).unwrap();
+    assert_eq!(globals(&mut ko, ["x", "y", "z"]), [None, Some(Value::Integer(4)), Some(Value::Integer(45))]);
+  }
+
+  #[test]
+  fn unknown_label_fails() {
+    let mut ko = koneko();
+    let error = run_program(&mut ko, "10 x = 1\n20 goto @nowhere").unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::UndefinedTarget);
+    assert_eq!(error.line_no(), Some(20));
+    assert_eq!(error.message(), "Goto: Unknown label @nowhere");
+  }
+
+  #[test]
+  fn duplicate_label_on_another_line_fails() {
+    let mut ko = koneko();
+    ko.basic.add_line("10 @a: x = 1".to_string()).unwrap();
+    let error = ko.basic.add_line("20 @a:".to_string()).unwrap_err();
+    assert_eq!(error.message(), "Duplicate label @a, already defined on line 10");
+    // the line holding the label can still be changed
+    ko.basic.add_line("10 @a: x = 2".to_string()).unwrap();
+
+    let errors = ko.basic.load_program("10 @a:\n20 @a:").unwrap_err();
+    assert_eq!(errors.len(), 1);
+  }
+
+  #[test]
+  fn comments_lex_with_their_span() {
+    let ko = koneko();
+    let (tokens, error) = ko.basic.lex_line("20 x = 1 ' one : x = 2");
+    assert_eq!(error, None);
+    assert_eq!(tokens.last(), Some(&(Token::Comment("one : x = 2".to_string()), 9, 22)));
+
+    let (tokens, _) = ko.basic.lex_line("30 rem   two");
+    assert_eq!(tokens.last(), Some(&(Token::Comment("two".to_string()), 3, 12)));
+  }
+
+  #[test]
+  fn comments_do_nothing_and_survive_save_and_load() {
+    let mut ko = koneko();
+    let source = "10 rem start\n20 x = 1 ' one : x = 2\n30 'rem x = 3\n40 rem x = 4";
+    run_program(&mut ko, source).unwrap();
+    assert_eq!(globals(&mut ko, ["x"]), [Some(Value::Integer(1))]);
+
+    let path = std::env::temp_dir().join("koneko_comments_test.bas");
+    let path = path.to_str().unwrap().replace('\\', "/");
+    run_line(&mut ko, &format!("save \"{}\"", path)).unwrap();
+    ko.basic.clear_program();
+    run_line(&mut ko, &format!("load \"{}\"", path)).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let contents = ko.basic.program.iter().map(|line| line.contents.as_str()).collect::<Vec<_>>();
+    assert_eq!(contents.join("\n"), source);
+  }
+
+  #[test]
+  fn slots_are_stable_per_name() {
+    let mut ko = koneko();
+    let a = ko.basic.slots.resolve("a");
+    let b = ko.basic.slots.resolve("b");
+    assert_ne!(a, b);
+    assert_eq!(ko.basic.slots.resolve("a"), a);
+    assert_eq!(ko.basic.slots.name(b), "b");
+
+    // changing the program keeps the slots handed out so far
+    ko.basic.load_program("10 c = 1\n20 b = 2").unwrap();
+    assert_eq!((ko.basic.slots.resolve("a"), ko.basic.slots.resolve("b")), (a, b));
+  }
+
+  #[test]
+  fn program_variables_are_visible_from_the_prompt() {
+    let mut ko = koneko();
+    run_program(&mut ko, "10 x = 5\n20 dim a[3]\n30 a[1] = 7").unwrap();
+    assert_eq!(run_line(&mut ko, "y = x + a[1]").unwrap(), Value::Integer(12));
+
+    // and the program sees what was set from the prompt
+    run_line(&mut ko, "z = 10").unwrap();
+    ko.basic.add_line("40 w = z * 2".to_string()).unwrap();
+    ko.basic.pc = ko.basic.line_pcs[&40];
+    ko.run_program(millis()).unwrap();
+    assert_eq!(globals(&mut ko, ["w"]), [Some(Value::Integer(20))]);
+  }
+
+  #[test]
+  fn reset_program_state_clears_variables() {
+    let mut ko = koneko();
+    run_program(&mut ko, "10 x = 5").unwrap();
+    ko.basic.reset_program_state();
+    assert_eq!(kind(run_line(&mut ko, "y = x")), ErrorKind::UndefinedVariable);
+  }
+
+  #[test]
+  fn false_while_skips_to_its_matching_loop() {
+    let mut ko = koneko();
+    let source = "10 while 0\n20 while 1\n30 x = 1\n40 loop\n50 loop\n60 y = 2";
+    run_program(&mut ko, source).unwrap();
+    assert_eq!(globals(&mut ko, ["x", "y"]), [None, Some(Value::Integer(2))]);
+  }
+
+  #[test]
+  fn nested_loops_pair_up() {
+    let mut ko = koneko();
+    let source = "\
+10 n = 0 : i = 0
+20 while i < 3
+30 for j = 0 to 2
+40 for k = 0 to 2 : n = n + 1 : next k
+50 next j
+60 i = i + 1
+70 loop";
+    run_program(&mut ko, source).unwrap();
+    assert_eq!(globals(&mut ko, ["n"]), [Some(Value::Integer(12))]);
+  }
+
+  #[test]
+  fn mismatched_blocks_are_found_before_running() {
+    let mut ko = koneko();
+    ko.basic.load_program("10 while 1\n20 for i = 0 to 2\n30 loop\n40 loop").unwrap();
+    assert_eq!(ko.basic.mismatches.len(), 2);
+    assert!(ko.basic.mismatches.contains(&(20, "For has no matching next".to_string())));
+    assert!(ko.basic.mismatches.contains(&(40, "Loop has no matching while".to_string())));
+    assert_eq!(ko.check(), 2);
+  }
+
+  #[test]
+  fn string_builtins() {
+    let mut ko = koneko();
+    let cases = [
+      (r#"len("héllo")"#, "5"),
+      (r#"left$("hello", 2) + right$("hello", 3)"#, "hello"),
+      (r#"left$("hi", 5) + right$("hi", 5)"#, "hihi"),
+      (r#"upper$("héllo") + lower$("ABC")"#, "HÉLLOabc"),
+      (r#"trim$("  a b  ")"#, "a b"),
+      (r#"replace$("a-b-c", "-", "+")"#, "a+b+c"),
+      (r#"asc("A") + asc("é")"#, "298"),
+      (r#"val(" 42 ") + val("1.5")"#, "43.5"),
+      (r#"split("a,b,,c", ",")"#, "{a, b, , c}"),
+      (r#"split("hé", "")"#, "{h, é}"),
+      (r#"join({1, "b", 2.5}, "-")"#, "1-b-2.5"),
+    ];
+    for (expr, expected) in cases {
+      assert_eq!(eval(&mut ko, expr).unwrap(), expected, "{}", expr);
+    }
+
+    // registered, so they're highlighted like the other builtins
+    let names = ["len", "mid$", "left$", "right$", "instr", "upper$", "lower$", "trim$", "replace$", "asc", "val", "split", "join"];
+    for name in names {
+      assert!(ko.basic.is_builtin_command(name), "{}", name);
+    }
+  }
+
+  #[test]
+  fn mid_and_instr_edge_cases() {
+    let mut ko = koneko();
+    let cases = [
+      (r#"mid$("hello", 1, 3)"#, "ell"),
+      (r#"mid$("hello", 3)"#, "lo"),
+      (r#"mid$("hello", 9, 2)"#, ""),
+      (r#"mid$("héllo", 1, 1)"#, "é"),
+      (r#"instr("hello", "l")"#, "2"),
+      (r#"instr("hello", "l", 3)"#, "3"),
+      (r#"instr("hello", "l", 4)"#, "-1"),
+      (r#"instr("hello", "z")"#, "-1"),
+      (r#"instr("héllo", "l")"#, "2"),
+      (r#"instr("hello", "", 2)"#, "2"),
+      (r#"instr("hello", "o", 99)"#, "-1"),
+    ];
+    for (expr, expected) in cases {
+      assert_eq!(eval(&mut ko, expr).unwrap(), expected, "{}", expr);
+    }
+
+    assert_eq!(kind(eval(&mut ko, r#"mid$("hello", -1)"#)), ErrorKind::InvalidArgument);
+    assert_eq!(kind(eval(&mut ko, r#"asc("")"#)), ErrorKind::InvalidArgument);
+    assert_eq!(kind(eval(&mut ko, r#"replace$("abc", "", "x")"#)), ErrorKind::InvalidArgument);
+    assert_eq!(kind(eval(&mut ko, r#"val("abc")"#)), ErrorKind::InvalidArgument);
+  }
+
+  #[test]
+  fn power_is_right_associative_and_binds_tightest() {
+    let mut ko = koneko();
+    let cases = [
+      ("2 ^ 3 ^ 2", "512"),
+      ("(2 ^ 3) ^ 2", "64"),
+      ("-2 ^ 2", "-4"),
+      ("2 * 3 ^ 2", "18"),
+      ("2 ^ 3 * 2", "16"),
+      ("1 + 2 ^ 2 % 3", "2"),
+      ("2 ^ 0.5", "1.4142135623730951"),
+      ("2.0 ^ 2", "4"),
+      ("2 ^ -1", "0"),
+      ("-1 ^ -3", "-1"),
+    ];
+    for (expr, expected) in cases {
+      assert_eq!(eval(&mut ko, expr).unwrap(), expected, "{}", expr);
+    }
+    assert!(matches!(run_line(&mut ko, "x = 2.0 ^ 2"), Ok(Value::Float(_))));
+
+    assert_eq!(kind(eval(&mut ko, "2 ^ 63")), ErrorKind::Overflow);
+    assert_eq!(kind(eval(&mut ko, "0 ^ -1")), ErrorKind::DivisionByZero);
+    assert_eq!(kind(eval(&mut ko, "\"a\" ^ 2")), ErrorKind::TypeMismatch);
+  }
+
+  #[test]
+  fn math_builtins_follow_integer_promotion() {
+    let mut ko = koneko();
+    let cases = [
+      ("sqrt(16)", "4"),
+      ("abs(-3)", "3"),
+      ("abs(-2.5)", "2.5"),
+      ("floor(2.7) + ceil(2.1)", "5"),
+      ("sgn(-4) + sgn(0) + sgn(2.5)", "0"),
+      ("min(3, 1, 2)", "1"),
+      ("max(3, 1.5)", "3"),
+      ("clamp(15, 0, 10)", "10"),
+      ("lerp(0, 10, 0.25)", "2.5"),
+      ("exp(0)", "1"),
+      ("log(1)", "0"),
+      ("tan(0)", "0"),
+      ("atan2(0, 1)", "0"),
+    ];
+    for (expr, expected) in cases {
+      assert_eq!(eval(&mut ko, expr).unwrap(), expected, "{}", expr);
+    }
+
+    assert!(matches!(run_line(&mut ko, "x = min(3, 1)"), Ok(Value::Integer(1))));
+    assert!(matches!(run_line(&mut ko, "x = min(3, 1.0)"), Ok(Value::Float(_))));
+    assert!(matches!(run_line(&mut ko, "x = abs(-3)"), Ok(Value::Integer(3))));
+    assert!(matches!(run_line(&mut ko, "x = floor(2.7)"), Ok(Value::Integer(2))));
+    assert_eq!(kind(eval(&mut ko, "sqrt(-1)")), ErrorKind::InvalidArgument);
+  }
+
+  #[test]
+  fn bitwise_builtins() {
+    let mut ko = koneko();
+    let cases = [
+      ("band(12, 10)", "8"),
+      ("bor(12, 10)", "14"),
+      ("bxor(12, 10)", "6"),
+      ("bnot(0)", "-1"),
+      ("shl(1, 4)", "16"),
+      ("shl(1, 63)", "-9223372036854775808"),
+      ("shl(1, 64)", "0"),
+      ("shr(256, 4)", "16"),
+      ("shr(-8, 1)", "-4"),
+      ("shr(-8, 99)", "-1"),
+      ("shr(8, 99)", "0"),
+      // unlike the logical operators, every bit counts
+      ("12 & 10", "1"),
+    ];
+    for (expr, expected) in cases {
+      assert_eq!(eval(&mut ko, expr).unwrap(), expected, "{}", expr);
+    }
+  }
+
+  #[test]
+  fn bitwise_builtins_reject_floats_and_strings() {
+    let mut ko = koneko();
+    for expr in ["band(1.5, 1)", "bor(1, \"2\")", "bnot(0.5)", "shl(1, 2.0)", "shr(\"8\", 1)"] {
+      assert_eq!(kind(eval(&mut ko, expr)), ErrorKind::TypeMismatch, "{}", expr);
     }
+    assert_eq!(kind(eval(&mut ko, "shl(1, -1)")), ErrorKind::InvalidArgument);
+  }
 
-    let res = self.interpret(self.basic.program[self.basic.line_no].node.clone());
-    if !self.basic.no_increment_instr_counter {
-      self.basic.line_no += 1;
+  #[test]
+  fn nested_arrays_read_and_write_at_any_depth() {
+    let mut ko = koneko();
+    let source = "\
+10 dim grid[2, 3]
+20 grid[1][2] = 5
+30 grid[0, 1] = 7
+40 cube = [2, 2, 2]
+50 cube[1][0][1] = 9
+60 a = grid[1, 2] + grid[0][1] + cube[1, 0, 1]";
+    run_program(&mut ko, source).unwrap();
+    assert_eq!(globals(&mut ko, ["a"]), [Some(Value::Integer(21))]);
+    assert_eq!(eval(&mut ko, "grid").unwrap(), "{{nil, 7, nil}, {nil, nil, 5}}");
+    assert_eq!(kind(eval(&mut ko, "grid[1][3]")), ErrorKind::OutOfBounds);
+    assert_eq!(kind(run_line(&mut ko, "grid[2][0] = 1")), ErrorKind::OutOfBounds);
+  }
+
+  #[test]
+  fn any_expression_can_be_indexed() {
+    let mut ko = koneko();
+    ko.basic.load_program("10 def f()\n20 return {{1, 2}, {3, 4}}\n30 end def").unwrap();
+    let cases = [
+      (r#"split("a,b", ",")[1]"#, "b"),
+      ("{10, 20}[1]", "20"),
+      ("f()[1][0]", "3"),
+      ("({5, 6})[0]", "5"),
+    ];
+    for (expr, expected) in cases {
+      assert_eq!(eval(&mut ko, expr).unwrap(), expected, "{}", expr);
     }
-    self.basic.no_increment_instr_counter = false;
-    res
   }
-}
\ No newline at end of file
+
+  #[test]
+  fn array_builtins_change_arrays_in_place() {
+    let mut ko = koneko();
+    let source = "\
+10 a = {3, 1}
+20 push a 2 5
+30 p = pop(a)
+40 insert a 0 9
+50 insert a len(a) 7
+60 r = remove(a, 1)
+70 sort a
+80 grid = {{1}, {2}}
+90 push grid[1] 3
+100 reverse grid[1]
+110 s = {\"b\", \"c\", \"a\"}
+120 sort s";
+    run_program(&mut ko, source).unwrap();
+    assert_eq!(eval(&mut ko, "{a, p, r, grid, s}").unwrap(), "{{1, 2, 7, 9}, 5, 3, {{1}, {3, 2}}, {a, b, c}}");
+  }
+
+  #[test]
+  fn array_builtins_give_new_arrays() {
+    let mut ko = koneko();
+    run_line(&mut ko, "a = {1, 2, 3, 4}").unwrap();
+    let cases = [
+      ("len(a)", "4"),
+      ("slice(a, 1, 3)", "{2, 3}"),
+      ("slice(a, 2)", "{3, 4}"),
+      ("slice(a, 3, 99)", "{4}"),
+      ("slice(a, 5)", "{}"),
+      ("find(a, 3)", "2"),
+      ("find(a, 9)", "-1"),
+      ("concat(a, {5}, {})", "{1, 2, 3, 4, 5}"),
+      // slice and concat leave the array alone
+      ("a", "{1, 2, 3, 4}"),
+    ];
+    for (expr, expected) in cases {
+      assert_eq!(eval(&mut ko, expr).unwrap(), expected, "{}", expr);
+    }
+  }
+
+  #[test]
+  fn array_builtins_reject_bad_arguments() {
+    let mut ko = koneko();
+    run_line(&mut ko, "a = {1, \"b\"}").unwrap();
+    assert_eq!(kind(run_line(&mut ko, "sort a")), ErrorKind::TypeMismatch);
+    assert_eq!(kind(run_line(&mut ko, "remove a 2")), ErrorKind::OutOfBounds);
+    assert_eq!(kind(run_line(&mut ko, "insert a 3 0")), ErrorKind::OutOfBounds);
+    run_line(&mut ko, "e = {}").unwrap();
+    assert_eq!(kind(run_line(&mut ko, "pop e")), ErrorKind::OutOfBounds);
+    assert_eq!(kind(run_line(&mut ko, "push 1 2")), ErrorKind::InvalidArgument);
+  }
+
+  #[test]
+  fn map_literals_print_with_keys_in_order() {
+    let mut ko = koneko();
+    let cases = [
+      (r#"{"b": 1, 2: "two", "a": {1, 2}, 1: {"x": 0}}"#, "{1: {x: 0}, 2: two, a: {1, 2}, b: 1}"),
+      ("{:}", "{:}"),
+      (r#"len({"a": 1, "b": 2})"#, "2"),
+      (r#"keys({"b": 1, 3: 0, "a": 2})"#, "{3, a, b}"),
+      (r#"has({"a": 1}, "a") + has({"a": 1}, 1)"#, "1"),
+    ];
+    for (expr, expected) in cases {
+      assert_eq!(eval(&mut ko, expr).unwrap(), expected, "{}", expr);
+    }
+  }
+
+  #[test]
+  fn maps_read_and_write_through_indexing() {
+    let mut ko = koneko();
+    let source = "\
+10 m = {\"hp\": 3}
+20 m[\"hp\"] = m[\"hp\"] - 1
+30 m[7] = \"seven\"
+40 m[\"inv\"] = {:}
+50 m[\"inv\"][\"key\"] = 1
+60 d = delete(m, 7)
+70 n = delete(m, \"nope\")";
+    run_program(&mut ko, source).unwrap();
+    assert_eq!(eval(&mut ko, "{m, d, n}").unwrap(), "{{hp: 2, inv: {key: 1}}, seven, nil}");
+    assert_eq!(eval(&mut ko, "m.hp").unwrap(), "2");
+
+    assert_eq!(kind(eval(&mut ko, r#"m["missing"]"#)), ErrorKind::OutOfBounds);
+    assert_eq!(kind(eval(&mut ko, "m[1.5]")), ErrorKind::TypeMismatch);
+  }
+
+  #[test]
+  fn records_hold_named_fields() {
+    let mut ko = koneko();
+    let source = "\
+10 type Ball
+20 x
+30 y
+40 end type
+50 b = Ball(1)
+60 b.x = b.x + 1
+70 b.y = 3.5
+80 balls = {Ball(0, 0), Ball(5, 6)}
+90 balls[1].x = balls[1].x * 10
+100 v = balls[1].x + balls[1].y";
+    run_program(&mut ko, source).unwrap();
+    assert_eq!(eval(&mut ko, "{b, v}").unwrap(), "{Ball{x: 2, y: 3.5}, 56}");
+    assert_eq!(eval(&mut ko, "Ball()").unwrap(), "Ball{x: nil, y: nil}");
+
+    assert_eq!(kind(eval(&mut ko, "b.z")), ErrorKind::OutOfBounds);
+    assert_eq!(kind(run_line(&mut ko, "b.z = 1")), ErrorKind::OutOfBounds);
+    assert_eq!(kind(eval(&mut ko, "Ball(1, 2, 3)")), ErrorKind::ArgumentCount);
+    assert_eq!(kind(eval(&mut ko, "Wall(1)")), ErrorKind::UndefinedFunction);
+  }
+
+  #[test]
+  fn dot_is_a_member_token_after_names_only() {
+    let ko = koneko();
+    let (tokens, error) = ko.basic.lex_line("b.x = .5 + 1.5");
+    assert_eq!(error, None);
+    let tokens = tokens.into_iter().map(|(token, _, _)| token).collect::<Vec<_>>();
+    assert_eq!(tokens[..3], [Token::Identifier("b".to_string()), Token::Dot, Token::Identifier("x".to_string())]);
+    assert_eq!(tokens[4..], [Token::Float(0.5), Token::Add, Token::Float(1.5)]);
+  }
+
+  #[test]
+  fn numeric_literals() {
+    let mut ko = koneko();
+    let cases = [
+      ("x = .5", Value::Float(0.5)),
+      ("x = 1e-3", Value::Float(0.001)),
+      ("x = 2.5E+1", Value::Float(25.0)),
+      ("x = 1e3", Value::Float(1000.0)),
+      ("x = &HFF", Value::Integer(255)),
+      ("x = 0Xff", Value::Integer(255)),
+      ("x = 0b1010", Value::Integer(10)),
+      ("x = 9223372036854775807", Value::Integer(i64::MAX)),
+    ];
+    for (line, expected) in cases {
+      assert_eq!(run_line(&mut ko, line).unwrap(), expected, "{}", line);
+    }
+
+    // an e without digits after it isn't an exponent
+    let (tokens, _) = ko.basic.lex_line("1e + 1");
+    assert_eq!(tokens[..2].iter().map(|it| it.0.clone()).collect::<Vec<_>>(), [Token::Integer(1), Token::Identifier("e".to_string())]);
+  }
+
+  #[test]
+  fn string_escapes() {
+    let ko = koneko();
+    let (tokens, error) = ko.basic.lex_line(r#"x = "a\"b\\c\nd\te\x41""#);
+    assert_eq!(error, None);
+    assert_eq!(tokens[2].0, Token::String("a\"b\\c\nd\teA".to_string()));
+
+    let cases = [
+      (r#"x = "\q""#, (5, 7)),
+      (r#"x = "\é""#, (5, 8)),
+      (r#"x = "\x4""#, (5, 7)),
+      (r#"x = "\"#, (5, 6)),
+    ];
+    for (src, span) in cases {
+      let error = ko.basic.lex_line(src).1.expect("expected a lex error");
+      assert_eq!((src, error.kind(), error.span()), (src, ErrorKind::Syntax, Some(span)));
+    }
+  }
+
+  #[test]
+  fn lexing_is_utf8_safe() {
+    let ko = koneko();
+    let (tokens, error) = ko.basic.lex_line(r#"x = "héllo ☃" + y"#);
+    assert_eq!(error, None);
+    assert_eq!(tokens[2], (Token::String("héllo ☃".to_string()), 4, 16));
+    assert_eq!(tokens[3], (Token::Add, 17, 18));
+    assert_eq!(tokens[4], (Token::Identifier("y".to_string()), 19, 20));
+  }
+
+  #[test]
+  fn text_is_measured_and_drawn_by_char() {
+    let mut ko = koneko();
+    // a char the font lacks takes up the placeholder glyph's width, once
+    assert_eq!(ko.width("a☃b"), ko.width("a?b"));
+    assert_eq!(ko.width("☃é☃"), ko.width("?é?"));
+    assert_eq!(ko.width("`4a`rb"), ko.width("ab"));
+    ko.text("héllo ☃", 0, 0, 7u8, None::<u8>, None::<u8>);
+  }
+
+  #[test]
+  fn prompt_edits_whole_chars() {
+    use sdl2::event::Event;
+    use sdl2::keyboard::{Keycode, Mod};
+
+    let key = |keycode| Event::KeyDown {
+      timestamp: 0,
+      window_id: 0,
+      keycode: Some(keycode),
+      scancode: None,
+      keymod: Mod::NOMOD,
+      repeat: false,
+    };
+
+    let mut ko = koneko();
+    ko.on_text_input(Event::TextInput { timestamp: 0, window_id: 0, text: "aé☃".to_string() });
+    assert_eq!((ko.current_line.as_str(), ko.cursor), ("aé☃", 6));
+    ko.on_key(key(Keycode::Left));
+    ko.on_key(key(Keycode::Backspace));
+    assert_eq!((ko.current_line.as_str(), ko.cursor), ("a☃", 1));
+    ko.on_text_input(Event::TextInput { timestamp: 0, window_id: 0, text: "ü".to_string() });
+    assert_eq!((ko.current_line.as_str(), ko.cursor), ("aü☃", 3));
+  }
+
+  #[test]
+  fn failed_load_reports_every_error_and_keeps_the_program() {
+    let mut ko = koneko();
+    ko.basic.load_program("10 x = 1\n20 print x").unwrap();
+
+    let errors = ko.basic.load_program("10 x = (1\n20 print \"ok\"\n30 y = \"a\\qb\"\n40 z = 2 +\nw = 3").unwrap_err();
+    let lines: Vec<_> = errors.iter().map(|error| error.line_no()).collect();
+    assert_eq!(lines, [Some(10), Some(30), Some(40), None]);
+    assert!(errors.iter().all(|error| error.kind() == ErrorKind::Syntax));
+
+    let contents: Vec<_> = ko.basic.program.iter().map(|line| line.contents.as_str()).collect();
+    assert_eq!(contents, ["10 x = 1", "20 print x"]);
+  }
+
+  #[test]
+  fn load_replaces_the_whole_program() {
+    let mut ko = koneko();
+    ko.basic.load_program("10 x = 1\n20 print x\n30 end").unwrap();
+    ko.basic.load_program("\n20 y = 2\n10 x = 3\n\n20 y = 4").unwrap();
+
+    let contents: Vec<_> = ko.basic.program.iter().map(|line| line.contents.as_str()).collect();
+    assert_eq!(contents, ["10 x = 3", "20 y = 4"]);
+  }
+
+  #[test]
+  fn read_walks_the_data_lines() {
+    let mut ko = koneko();
+    let source = "\
+10 dim a[3]
+20 read x, s$, a[0], a[1], a[2]
+30 data 1, \"two\"
+40 print x
+50 data -3.5, -4, &hff";
+    run_program(&mut ko, source).unwrap();
+    let [x, s] = globals(&mut ko, ["x", "s$"]);
+    assert_eq!((x, s), (Some(Value::Integer(1)), Some(Value::String("two".to_string()))));
+    assert_eq!(eval(&mut ko, "a").unwrap(), "{-3.5, -4, 255}");
+  }
+
+  #[test]
+  fn restore_rewinds_to_a_line_or_label() {
+    let mut ko = koneko();
+    let source = "\
+10 read a, b
+20 restore
+30 read c
+40 restore @second
+50 read d
+60 restore 80
+70 read e
+80 data 1, 2
+90 @second: data 3";
+    run_program(&mut ko, source).unwrap();
+    let values = globals(&mut ko, ["a", "b", "c", "d", "e"]);
+    assert_eq!(values, [1, 2, 1, 3, 1].map(|it| Some(Value::Integer(it))));
+
+    assert_eq!(kind(run_program(&mut ko, "10 restore 15\n20 data 1")), ErrorKind::UndefinedTarget);
+  }
+
+  #[test]
+  fn reading_past_the_data_fails() {
+    let mut ko = koneko();
+    let error = run_program(&mut ko, "10 data 1\n20 read a, b").unwrap_err();
+    assert_eq!((error.kind(), error.line_no()), (ErrorKind::OutOfData, Some(20)));
+
+    // a line with no data after it leaves nothing to read
+    assert_eq!(kind(run_program(&mut ko, "10 data 1\n20 restore 30\n30 read a")), ErrorKind::OutOfData);
+  }
+
+  #[test]
+  fn data_pointer_is_reset_with_the_program_state() {
+    let mut ko = koneko();
+    run_program(&mut ko, "10 read a\n20 data 7, 8").unwrap();
+    run_line(&mut ko, "read b").unwrap();
+    assert_eq!(globals(&mut ko, ["b"]), [Some(Value::Integer(8))]);
+
+    ko.basic.reset_program_state();
+    run_line(&mut ko, "read c").unwrap();
+    assert_eq!(globals(&mut ko, ["c"]), [Some(Value::Integer(7))]);
+  }
+
+  #[test]
+  fn data_holds_only_literals() {
+    let mut ko = koneko();
+    assert_eq!(kind(ko.basic.add_line("10 data 1, x".to_string())), ErrorKind::Syntax);
+    assert_eq!(kind(ko.basic.add_line("10 data 1 2".to_string())), ErrorKind::Syntax);
+    assert_eq!(kind(ko.basic.add_line("10 data".to_string())), ErrorKind::Syntax);
+    assert_eq!(kind(ko.basic.add_line("10 read 1".to_string())), ErrorKind::Syntax);
+  }
+}
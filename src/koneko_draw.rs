@@ -1,5 +1,6 @@
 use std::cmp::{max, min};
-use crate::koneko::{Character, COLOR_PREFIX, HEIGHT, Koneko, WIDTH};
+use crate::koneko::{Character, COLOR_PREFIX, HEIGHT, Koneko, PLACEHOLDER_CHAR, WIDTH};
+use crate::koneko_error::{ErrorKind, KonekoError};
 
 impl Koneko {
   pub fn cls(&mut self, color: impl Into<u8> + Copy) {
@@ -67,9 +68,10 @@ impl Koneko {
     }
   }
 
-  pub fn poly(&mut self, vertices: Vec<(i32, i32)>, color: impl Into<u8> + Copy) -> Result<(), String> {
+  pub fn poly(&mut self, vertices: Vec<(i32, i32)>, color: impl Into<u8> + Copy) -> Result<(), KonekoError> {
     if vertices.len() < 3 {
-      return Err(format!("Polygon must have at least 3 vertices, got {}", vertices.len()));
+      let message = format!("Polygon must have at least 3 vertices, got {}", vertices.len());
+      return Err(KonekoError::runtime(ErrorKind::InvalidArgument, message));
     }
 
     let mut min_x = i32::MAX;
@@ -129,9 +131,10 @@ impl Koneko {
     Ok(())
   }
 
-  pub fn outline(&mut self, vertices: Vec<(i32, i32)>, color: impl Into<u8> + Copy) -> Result<(), String> {
+  pub fn outline(&mut self, vertices: Vec<(i32, i32)>, color: impl Into<u8> + Copy) -> Result<(), KonekoError> {
     if vertices.len() < 3 {
-      return Err(format!("Polygon must have at least 3 vertices, got {}", vertices.len()));
+      let message = format!("Polygon must have at least 3 vertices, got {}", vertices.len());
+      return Err(KonekoError::runtime(ErrorKind::InvalidArgument, message));
     }
 
     for i in 0..vertices.len() {
@@ -156,22 +159,32 @@ impl Koneko {
     }
   }
 
+  // the glyph for char, where chars the font doesn't have past ascii are drawn as a placeholder
+  // and the rest, like space, are left blank
+  fn glyph(&self, char: char) -> Character {
+    match self.char_info.get(char as usize) {
+      Some(char_info) if *char_info != Character::invalid() => *char_info,
+      _ if char.is_ascii() => Character::invalid(),
+      _ => self.char_info[PLACEHOLDER_CHAR as usize],
+    }
+  }
+
   pub fn text_impl(&mut self, text: &str, mut x: i32, y: i32, shadow: bool, color: impl Into<u8> + Copy, clear_background: Option<impl Into<u8> + Copy>) {
     let mut color = color.into();
     let orig_color = color.into();
-    let mut prev_char = b'\0';
-    for char in text.bytes() {
-      if char == COLOR_PREFIX {
+    let mut prev_char = '\0';
+    for char in text.chars() {
+      if char == COLOR_PREFIX as char {
         prev_char = char;
         continue;
       }
 
-      if prev_char == COLOR_PREFIX {
+      if prev_char == COLOR_PREFIX as char {
         prev_char = char;
-        if let Some(new_color) = Self::one_digit_hex(char) {
+        if let Some(new_color) = u8::try_from(char).ok().and_then(Self::one_digit_hex) {
           color = new_color;
           continue;
-        } else if char == b'r' {
+        } else if char == 'r' {
           color = orig_color;
           continue;
         }
@@ -182,12 +195,7 @@ impl Koneko {
         color = orig_color;
       }
 
-      let char = char as usize;
-      if char >= 256 {
-        continue;
-      }
-
-      let char_info = self.char_info[char];
+      let char_info = self.glyph(char);
       if char_info == Character::invalid() {
         x += 5;
         continue;
@@ -223,27 +231,23 @@ impl Koneko {
   
   pub fn width(&self, text: &str) -> i32 {
     let mut width = 0;
-    let mut prev_char = b'\0';
-    for char in text.bytes() {
-      if char == COLOR_PREFIX {
+    let mut prev_char = '\0';
+    for char in text.chars() {
+      if char == COLOR_PREFIX as char {
         prev_char = char;
         continue;
       }
 
-      if prev_char == COLOR_PREFIX {
+      if prev_char == COLOR_PREFIX as char {
         prev_char = char;
-        if let Some(_) = Self::one_digit_hex(char) {
-          continue;
-        } else if char == b'r' {
+        if u8::try_from(char).ok().and_then(Self::one_digit_hex).is_some() || char == 'r' {
           continue;
         }
       }
 
       prev_char = char;
 
-      let char = char as usize;
-
-      let char_info = self.char_info[char];
+      let char_info = self.glyph(char);
       if char_info == Character::invalid() {
         width += 5;
         continue;
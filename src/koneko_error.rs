@@ -0,0 +1,127 @@
+use std::fmt;
+
+// what went wrong, independent of where, so embedders can match on it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+  Syntax,
+  // the line couldn't be lexed or parsed
+  UndefinedVariable,
+  UndefinedFunction,
+  // an unknown function, builtin or type
+  UndefinedTarget,
+  // a goto, gosub, restore or on error line or label that doesn't exist
+  TypeMismatch,
+  ArgumentCount,
+  InvalidArgument,
+  // the right type, but a value the operation can't take, like sqrt(-1)
+  DivisionByZero,
+  Overflow,
+  OutOfBounds,
+  // an array index, map key or record field that isn't there
+  OutOfData,
+  Misplaced,
+  // a statement outside the block or context it needs, like a loop without a while
+  StackOverflow,
+  Timeout,
+  Io,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum KonekoError {
+  Lex {
+    kind: ErrorKind,
+    message: String,
+    line_no: Option<usize>,
+    span: (usize, usize),
+  },
+  // the line couldn't be split into tokens; span is the byte range of the offending text
+  Parse {
+    kind: ErrorKind,
+    message: String,
+    line_no: Option<usize>,
+    span: (usize, usize),
+  },
+  // the tokens don't make up a statement; span is the offending token, or the end of the line
+  Runtime {
+    kind: ErrorKind,
+    message: String,
+    line_no: Option<usize>,
+    span: Option<(usize, usize)>,
+    gosubs: Vec<usize>,
+  },
+  // a statement failed while running; span is the statement, and gosubs holds the lines of the
+  // gosubs it was reached through, outermost first. line_no and span are None for the immediate
+  // mode line
+}
+
+impl KonekoError {
+  pub fn lex(kind: ErrorKind, message: String, span: (usize, usize)) -> KonekoError {
+    KonekoError::Lex { kind, message, line_no: None, span }
+  }
+
+  pub fn parse(kind: ErrorKind, message: String, span: (usize, usize)) -> KonekoError {
+    KonekoError::Parse { kind, message, line_no: None, span }
+  }
+
+  pub fn runtime(kind: ErrorKind, message: String) -> KonekoError {
+    KonekoError::Runtime { kind, message, line_no: None, span: None, gosubs: vec![] }
+  }
+
+  pub fn kind(&self) -> ErrorKind {
+    match self {
+      KonekoError::Lex { kind, .. }
+      | KonekoError::Parse { kind, .. }
+      | KonekoError::Runtime { kind, .. } => *kind,
+    }
+  }
+
+  pub fn message(&self) -> &str {
+    match self {
+      KonekoError::Lex { message, .. }
+      | KonekoError::Parse { message, .. }
+      | KonekoError::Runtime { message, .. } => message,
+    }
+  }
+
+  pub fn line_no(&self) -> Option<usize> {
+    match self {
+      KonekoError::Lex { line_no, .. }
+      | KonekoError::Parse { line_no, .. }
+      | KonekoError::Runtime { line_no, .. } => *line_no,
+    }
+  }
+
+  // the byte range of the line the error points at, if it points anywhere
+  pub fn span(&self) -> Option<(usize, usize)> {
+    match self {
+      KonekoError::Lex { span, .. } | KonekoError::Parse { span, .. } => Some(*span),
+      KonekoError::Runtime { span, .. } => *span,
+    }
+  }
+
+  pub fn with_line_no(mut self, line_no: Option<usize>) -> KonekoError {
+    match &mut self {
+      KonekoError::Lex { line_no: it, .. }
+      | KonekoError::Parse { line_no: it, .. }
+      | KonekoError::Runtime { line_no: it, .. } => *it = line_no,
+    }
+    self
+  }
+}
+
+impl fmt::Display for KonekoError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    if let Some(line_no) = self.line_no() {
+      write!(f, "Line {}: ", line_no)?;
+    }
+    write!(f, "{}", self.message())?;
+
+    if let KonekoError::Runtime { gosubs, .. } = self {
+      if !gosubs.is_empty() {
+        let lines = gosubs.iter().rev().map(|it| it.to_string()).collect::<Vec<_>>();
+        write!(f, " (gosub from {})", lines.join(", "))?;
+      }
+    }
+    Ok(())
+  }
+}
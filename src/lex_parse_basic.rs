@@ -1,5 +1,10 @@
 use std::cmp::Ordering;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Deref;
+use std::rc::Rc;
+
+use crate::compile_basic::{Function, Op, Slots};
+use crate::koneko_error::{ErrorKind, KonekoError};
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Value {
@@ -7,9 +12,54 @@ pub enum Value {
   Integer(i64),
   Float(f64),
   Array(Vec<Value>),
+  Map(BTreeMap<Key, Value>),
+  Record(Rc<RecordType>, Vec<Value>),
+  // one value per field of the type
   Nil,
 }
 
+// declared with type name ... end type, one field name per line
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordType {
+  pub name: String,
+  pub fields: Vec<String>,
+}
+
+impl RecordType {
+  pub fn field(&self, name: &Value) -> Result<usize, KonekoError> {
+    let Value::String(name) = name else {
+      return Err(KonekoError::runtime(ErrorKind::TypeMismatch, format!("Expected field name, got {:?}", name)));
+    };
+
+    self.fields.iter().position(|it| it == name)
+      .ok_or_else(|| KonekoError::runtime(ErrorKind::OutOfBounds, format!("Type {} has no field {}", self.name, name)))
+  }
+}
+
+// maps are keyed by integers or strings, and keep their keys in order
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Key {
+  Integer(i64),
+  String(String),
+}
+
+impl Key {
+  pub fn from_value(value: &Value) -> Result<Key, KonekoError> {
+    match value {
+      Value::Integer(num) => Ok(Key::Integer(*num)),
+      Value::String(string) => Ok(Key::String(string.clone())),
+      _ => Err(KonekoError::runtime(ErrorKind::TypeMismatch, format!("Expected integer or string key, got {:?}", value))),
+    }
+  }
+
+  pub fn to_value(&self) -> Value {
+    match self {
+      Key::Integer(num) => Value::Integer(*num),
+      Key::String(string) => Value::String(string.clone()),
+    }
+  }
+}
+
 impl Value {
   pub fn to_string(&self, delimiters: bool) -> String {
     match self {
@@ -34,6 +84,22 @@ impl Value {
         }
         string
       }
+      Value::Map(map) => {
+        if map.is_empty() {
+          return "{:}".to_string();
+        }
+
+        let entries = map.iter()
+          .map(|(key, value)| format!("{}: {}", key.to_value().to_string(delimiters), value.to_string(delimiters)))
+          .collect::<Vec<_>>();
+        format!("{{{}}}", entries.join(", "))
+      }
+      Value::Record(record, values) => {
+        let fields = record.fields.iter().zip(values)
+          .map(|(field, value)| format!("{}: {}", field, value.to_string(delimiters)))
+          .collect::<Vec<_>>();
+        format!("{}{{{}}}", record.name, fields.join(", "))
+      }
       Value::Nil => "nil".to_string(),
     }
   }
@@ -44,47 +110,60 @@ impl Value {
       Value::Integer(num) => *num != 0,
       Value::Float(num) => *num != 0.0,
       Value::Array(array) => !array.is_empty(),
+      Value::Map(map) => !map.is_empty(),
+      Value::Record(..) => true,
       Value::Nil => false,
     }
   }
 
-  pub fn comparison_value(&self) -> Result<f64, String> {
+  pub fn comparison_value(&self) -> Result<f64, KonekoError> {
     match self {
-      Value::String(string) => Err(format!("Cannot compare string {:?}!", string)),
+      Value::String(string) => Err(KonekoError::runtime(ErrorKind::TypeMismatch, format!("Cannot compare string {:?}!", string))),
       Value::Integer(num) => Ok(*num as f64),
       Value::Float(num) => Ok(*num),
-      Value::Array(array) => Err(format!("Cannot compare array {:?}!", array)),
+      Value::Array(array) => Err(KonekoError::runtime(ErrorKind::TypeMismatch, format!("Cannot compare array {:?}!", array))),
+      Value::Map(map) => Err(KonekoError::runtime(ErrorKind::TypeMismatch, format!("Cannot compare map {:?}!", map))),
+      Value::Record(record, _) => Err(KonekoError::runtime(ErrorKind::TypeMismatch, format!("Cannot compare {}!", record.name))),
       Value::Nil => Ok(0.0),
     }
   }
 
-  pub fn to_integer(&self) -> Result<i64, String> {
+  pub fn to_integer(&self) -> Result<i64, KonekoError> {
     match self {
-      Value::String(string) => Ok(string.parse::<i64>().unwrap()),
+      Value::String(string) => string.parse::<i64>()
+        .map_err(|_| KonekoError::runtime(ErrorKind::InvalidArgument, format!("Cannot convert string {:?} to integer!", string))),
       Value::Integer(num) => Ok(*num),
       Value::Float(num) => Ok(num.round() as i64),
       Value::Nil => Ok(0),
-      Value::Array(array) => Err(format!("Cannot convert array {:?} to integer!", array)),
+      Value::Array(array) => Err(KonekoError::runtime(ErrorKind::TypeMismatch, format!("Cannot convert array {:?} to integer!", array))),
+      Value::Map(map) => Err(KonekoError::runtime(ErrorKind::TypeMismatch, format!("Cannot convert map {:?} to integer!", map))),
+      Value::Record(record, _) => Err(KonekoError::runtime(ErrorKind::TypeMismatch, format!("Cannot convert {} to integer!", record.name))),
     }
   }
 
-  pub fn to_integer_raw(&self) -> Result<i64, String> {
+  pub fn to_integer_raw(&self) -> Result<i64, KonekoError> {
     match self {
-      Value::String(string) => Ok(string.parse::<i64>().unwrap()),
+      Value::String(string) => string.parse::<i64>()
+        .map_err(|_| KonekoError::runtime(ErrorKind::InvalidArgument, format!("Cannot convert string {:?} to integer!", string))),
       Value::Integer(num) => Ok(*num),
       Value::Float(num) => Ok(*num as i64),
       Value::Nil => Ok(0),
-      Value::Array(array) => Err(format!("Cannot convert array {:?} to integer!", array)),
+      Value::Array(array) => Err(KonekoError::runtime(ErrorKind::TypeMismatch, format!("Cannot convert array {:?} to integer!", array))),
+      Value::Map(map) => Err(KonekoError::runtime(ErrorKind::TypeMismatch, format!("Cannot convert map {:?} to integer!", map))),
+      Value::Record(record, _) => Err(KonekoError::runtime(ErrorKind::TypeMismatch, format!("Cannot convert {} to integer!", record.name))),
     }
   }
 
-  pub fn to_float(&self) -> Result<f64, String> {
+  pub fn to_float(&self) -> Result<f64, KonekoError> {
     match self {
-      Value::String(string) => Ok(string.parse::<f64>().unwrap()),
+      Value::String(string) => string.parse::<f64>()
+        .map_err(|_| KonekoError::runtime(ErrorKind::InvalidArgument, format!("Cannot convert string {:?} to float!", string))),
       Value::Integer(num) => Ok(*num as f64),
       Value::Float(num) => Ok(*num),
       Value::Nil => Ok(0.0),
-      Value::Array(array) => Err(format!("Cannot convert array {:?} to float!", array)),
+      Value::Array(array) => Err(KonekoError::runtime(ErrorKind::TypeMismatch, format!("Cannot convert array {:?} to float!", array))),
+      Value::Map(map) => Err(KonekoError::runtime(ErrorKind::TypeMismatch, format!("Cannot convert map {:?} to float!", map))),
+      Value::Record(record, _) => Err(KonekoError::runtime(ErrorKind::TypeMismatch, format!("Cannot convert {} to float!", record.name))),
     }
   }
 }
@@ -100,10 +179,64 @@ pub enum Node {
   // for name = start to end step step
   If {
     cond: Box<Node>,
-    then: Box<Node>,
-    else_: Box<Node>,
+    then: Vec<Node>,
+    else_: Vec<Node>,
   },
-  // if cond then then else else
+  // if cond then then else else, where each branch holds every statement up to the else or the
+  // end of the line
+  BlockIf {
+    cond: Box<Node>,
+  },
+  // if cond then
+  ElseIf {
+    cond: Box<Node>,
+  },
+  // elseif cond then
+  Else,
+  // else
+  EndIf,
+  // end if
+  Def {
+    name: String,
+    params: Vec<String>,
+  },
+  // def name(param1, param2, ... paramN)
+  EndDef,
+  // end def
+  Type(String),
+  // type name
+  EndType,
+  // end type
+  Return(Box<Node>),
+  // return value
+  Local(Vec<String>),
+  // local name1, name2, ... nameN
+  Data(Vec<Value>),
+  // data value1, value2, ... valueN
+  Read(Vec<Node>),
+  // read var1, var2[index], ... varN
+  Restore(Box<Node>),
+  // restore [target], where a target of nil rewinds to the first data line
+  Input {
+    prompt: Box<Node>,
+    target: Box<Node>,
+  },
+  // input prompt; var
+  Call {
+    name: String,
+    args: Vec<Node>,
+  },
+  // name(arg1, arg2, ... argN)
+  Label(String),
+  // @name:
+  Comment(String),
+  // rem text, ' text
+  OnError(Box<Node>),
+  // on error gosub target
+  Resume(bool),
+  // resume [next]
+  LabelRef(String),
+  // @name
   Assign {
     name: String,
     value: Box<Node>,
@@ -135,19 +268,21 @@ pub enum Node {
   // var
   Array(Vec<Node>),
   // {node1, node2, ... nodeN}
-  EmptyArray(Box<Node>),
-  // [size]
+  Map(Vec<(Node, Node)>),
+  // {key1: value1, ... keyN: valueN}, or {:} when empty
+  EmptyArray(Vec<Node>),
+  // [size1, size2, ... sizeN], nested N deep
   IndexGet {
-    name: String,
+    target: Box<Node>,
     index: Box<Node>,
   },
-  // name[index]
+  // target[index]
   IndexSet {
     name: String,
-    index: Box<Node>,
+    indices: Vec<Node>,
     value: Box<Node>,
   },
-  // name[index] = value
+  // name[index1][index2]...[indexN] = value, where .field is an index of "field"
   End,
   // end
   Nil,
@@ -176,8 +311,14 @@ pub enum Token {
   Mul,
   Div,
   Percent,
+  Caret,
   Comma,
+  Colon,
+  Semicolon,
+  Dot,
   Identifier(String),
+  Label(String),
+  Comment(String),
   For,
   Next,
   To,
@@ -190,10 +331,47 @@ pub enum Token {
   Exclamation,
 }
 
+// a lexed line, keeping where each token came from so parse errors can point at it. it derefs
+// to the tokens themselves
+#[derive(Debug, Clone)]
+pub struct Tokens {
+  tokens: Vec<Token>,
+  spans: Vec<(usize, usize)>,
+  len: usize,
+  // the length of the line, where a missing token would have been
+}
+
+impl Tokens {
+  pub fn new(lexed: Vec<(Token, usize, usize)>, len: usize) -> Tokens {
+    let (tokens, spans) = lexed.into_iter().map(|(token, begin, end)| (token, (begin, end))).unzip();
+    Tokens { tokens, spans, len }
+  }
+
+  // the byte range of the token at idx, or an empty one at the end of the line past the last token
+  pub fn span(&self, idx: usize) -> (usize, usize) {
+    self.spans.get(idx).copied().unwrap_or((self.len, self.len))
+  }
+
+  // a syntax error pointing at the token at idx
+  pub fn error(&self, idx: usize, message: String) -> KonekoError {
+    KonekoError::parse(ErrorKind::Syntax, message, self.span(idx))
+  }
+}
+
+impl Deref for Tokens {
+  type Target = [Token];
+
+  fn deref(&self) -> &[Token] {
+    &self.tokens
+  }
+}
+
 #[derive(Debug, Clone)]
 pub struct Line {
   pub line_no: usize,
-  pub node: Node,
+  pub nodes: Vec<Node>,
+  pub spans: Vec<(usize, usize)>,
+  // the byte range of each statement in contents
   pub contents: String,
 }
 
@@ -218,23 +396,64 @@ impl Ord for Line {
 }
 
 static INVALID_LINE_NO: usize = 0;
+pub static MAX_CALL_DEPTH: usize = 256;
+pub static MAX_ARRAY_LEN: usize = 1 << 24;
+
+#[derive(Debug, Clone)]
+pub struct Frame {
+  // slots declared local to this call, unset until assigned
+  pub locals: Vec<(usize, Option<Value>)>,
+  pub function: usize,
+  pub return_pc: usize,
+  pub stmt_pc: usize,
+  // depths of the stacks on entry, restored when the call returns
+  pub stack_base: usize,
+  pub call_depth: usize,
+  pub while_depth: usize,
+  pub for_depth: usize,
+}
 
 pub struct ParseOptions {
   pub builtin_commands: Vec<&'static str>,
-  pub builtin_vars: Vec<&'static str>
+  pub builtin_vars: Vec<&'static str>,
+  pub builtin_arities: HashMap<&'static str, (usize, usize)>,
+  // least and most arguments each builtin takes
 }
 
 pub struct BASIC {
   pub program: Vec<Line>,
-  pub vars: HashMap<String, Value>,
-  pub line_no: usize,
+  pub vars: Vec<Option<Value>>,
+  pub slots: Slots,
+  pub code: Rc<Vec<Op>>,
+  pub program_end: usize,
+  pub program_code_len: usize,
+  pub pc: usize,
+  pub stmt_pc: usize,
+  pub stack: Vec<Value>,
   pub call_stack: Vec<usize>,
+  pub frames: Vec<Frame>,
+  pub functions: Vec<Function>,
+  pub function_names: HashMap<String, usize>,
+  pub types: HashMap<String, Rc<RecordType>>,
+  pub line_pcs: HashMap<usize, usize>,
+  pub label_pcs: HashMap<String, usize>,
+  pub label_lines: HashMap<String, usize>,
+  pub line_indices: HashMap<usize, usize>,
+  pub mismatches: Vec<(usize, String)>,
+  // blocks missing their start or end, reported before the program runs
+  pub data: Vec<Value>,
+  pub data_lines: BTreeMap<usize, usize>,
+  // the index in data of each data line's first value
+  pub data_pointer: usize,
+  pub error_handler: Option<usize>,
+  pub error_line: Option<(usize, usize)>,
+  // the statement the running error handler was entered from, and the call_stack depth under
+  // the return address it pushed
   pub while_stack: Vec<usize>,
   pub for_stack: Vec<(usize, Value, Value)>,
   pub symbols: HashMap<u8, Token>,
   pub keywords: HashMap<&'static str, Token>,
   pub options: ParseOptions,
-  pub no_increment_instr_counter: bool,
   pub refresh: bool,
 }
 
@@ -246,15 +465,34 @@ impl BASIC {
   ) -> BASIC {
     BASIC {
       program: Vec::<Line>::new(),
-      vars: HashMap::<String, Value>::new(),
-      line_no: 0,
+      vars: Vec::<Option<Value>>::new(),
+      slots: Slots::default(),
+      code: Rc::new(vec![Op::EndOfProgram]),
+      program_end: 0,
+      program_code_len: 1,
+      pc: 0,
+      stmt_pc: 0,
+      stack: Vec::<Value>::new(),
       call_stack: Vec::<usize>::new(),
+      frames: Vec::<Frame>::new(),
+      functions: Vec::<Function>::new(),
+      function_names: HashMap::<String, usize>::new(),
+      types: HashMap::<String, Rc<RecordType>>::new(),
+      line_pcs: HashMap::<usize, usize>::new(),
+      label_pcs: HashMap::<String, usize>::new(),
+      label_lines: HashMap::<String, usize>::new(),
+      line_indices: HashMap::<usize, usize>::new(),
+      mismatches: Vec::<(usize, String)>::new(),
+      data: Vec::<Value>::new(),
+      data_lines: BTreeMap::<usize, usize>::new(),
+      data_pointer: 0,
+      error_handler: None,
+      error_line: None,
       while_stack: Vec::<usize>::new(),
       for_stack: Vec::<(usize, Value, Value)>::new(),
       symbols,
       keywords,
       options,
-      no_increment_instr_counter: false,
       refresh: false,
     }
   }
@@ -263,113 +501,307 @@ impl BASIC {
     self.options.builtin_commands.contains(&str)
   }
 
-  pub fn add_line(&mut self, src: String) -> Result<Option<Node>, String> {
+  fn lex_parse_line(&self, src: String) -> Result<Line, KonekoError> {
     let (tokens, error) = self.lex_line(&src);
-    if !error.is_empty() {
+    if let Some(mut error) = error {
+      // the line number is lexed before anything can go wrong after it
+      if let Some(&(Token::Integer(num), begin, end)) = tokens.first() {
+        if Self::is_line_no(&src[begin..end]) {
+          error = error.with_line_no(Some(num as usize));
+        }
+      }
       return Err(error);
     }
 
-    let parse_res = self.parse_line(&tokens.iter().map(|it| -> Token { it.0.clone() } ).collect(), src);
-    if let Err(e) = parse_res {
-      return Err(e);
-    }
+    self.parse_line(&Tokens::new(tokens, src.len()), src)
+  }
 
-    let line = parse_res.unwrap();
+  pub fn add_line(&mut self, src: String) -> Result<Option<Vec<Node>>, KonekoError> {
+    let line = self.lex_parse_line(src)?;
 
     if line.line_no == 0 {
-      return Ok(Some(line.node));
+      return Ok(Some(line.nodes));
     }
 
-    if line.node == Node::Nil {
+    if line.nodes.is_empty() {
       self.remove_line(line.line_no);
       return Ok(None);
     }
 
-    if let Some(idx) = self.program.iter().position(|x| x.line_no == line.line_no) {
+    for (node, span) in line.nodes.iter().zip(&line.spans) {
+      if let Node::Label(name) = node {
+        if let Some(&line_no) = self.label_lines.get(name) {
+          if line_no != line.line_no {
+            let message = format!("Duplicate label @{}, already defined on line {}", name, line_no);
+            return Err(KonekoError::parse(ErrorKind::Syntax, message, *span).with_line_no(Some(line.line_no)));
+          }
+        }
+      }
+    }
+
+    if let Some(&idx) = self.line_indices.get(&line.line_no) {
       self.program[idx] = line;
     } else {
       self.program.push(line);
       self.program.sort();
     }
+    self.compile();
 
     Ok(None)
   }
 
+  // replaces the whole program, but only if every line of the source is good. otherwise the
+  // old program is kept and every bad line is reported
+  pub fn load_program(&mut self, source: &str) -> Result<(), Vec<KonekoError>> {
+    let mut lines = BTreeMap::new();
+    let mut label_lines = HashMap::new();
+    let mut errors = vec![];
+
+    for src in source.lines() {
+      if src.trim().is_empty() {
+        continue;
+      }
+
+      let line = match self.lex_parse_line(src.to_string()) {
+        Ok(line) => line,
+        Err(error) => {
+          errors.push(error);
+          continue;
+        }
+      };
+
+      if line.line_no == 0 {
+        let message = format!("Expected a line number, got {}", src.trim());
+        errors.push(KonekoError::parse(ErrorKind::Syntax, message, (0, 0)));
+        continue;
+      }
+
+      for (node, span) in line.nodes.iter().zip(&line.spans) {
+        if let Node::Label(name) = node {
+          match label_lines.get(name) {
+            Some(&line_no) if line_no != line.line_no => {
+              let message = format!("Duplicate label @{}, already defined on line {}", name, line_no);
+              errors.push(KonekoError::parse(ErrorKind::Syntax, message, *span).with_line_no(Some(line.line_no)));
+            }
+            _ => {
+              label_lines.insert(name.clone(), line.line_no);
+            }
+          }
+        }
+      }
+
+      // like typing the lines in, a later line replaces an earlier one with the same number
+      if line.nodes.is_empty() {
+        lines.remove(&line.line_no);
+      } else {
+        lines.insert(line.line_no, line);
+      }
+    }
+
+    if !errors.is_empty() {
+      return Err(errors);
+    }
+
+    self.program = lines.into_values().collect();
+    self.compile();
+    Ok(())
+  }
+
   fn remove_line(&mut self, line_no: usize) {
-    self.program.retain(|x| x.line_no != line_no);
+    if let Some(&idx) = self.line_indices.get(&line_no) {
+      self.program.remove(idx);
+      self.compile();
+    }
+  }
+
+  pub fn clear_program(&mut self) {
+    self.program.clear();
+    self.compile();
+  }
+
+  // the start of the statement following the one at pc, in program order
+  pub fn next_stmt(&self, pc: usize) -> usize {
+    (pc + 1..self.program_end)
+      .find(|&pc| matches!(self.code[pc], Op::Stmt(..)))
+      .unwrap_or(self.program_end)
+  }
+
+  pub fn last_stmt(&self) -> usize {
+    (0..self.program_end)
+      .rfind(|&pc| matches!(self.code[pc], Op::Stmt(..)))
+      .unwrap_or(self.program_end)
+  }
+
+  pub fn pop(&mut self) -> Value {
+    self.stack.pop().unwrap_or(Value::Nil)
+  }
+
+  // abandons every function call in progress, going back to the outermost statement
+  pub fn unwind(&mut self) {
+    if let Some(frame) = self.frames.first() {
+      self.call_stack.truncate(frame.call_depth);
+      self.while_stack.truncate(frame.while_depth);
+      self.for_stack.truncate(frame.for_depth);
+      self.stmt_pc = frame.stmt_pc;
+    }
+    self.frames.clear();
+    self.stack.clear();
+  }
+
+  // the innermost call's copy of slot if it was declared local there, else the global one
+  fn scope(&self, slot: usize) -> Option<&Option<Value>> {
+    match self.frames.last().and_then(|frame| frame.locals.iter().find(|(it, _)| *it == slot)) {
+      Some((_, value)) => Some(value),
+      None => self.vars.get(slot),
+    }
+  }
+
+  fn scope_mut(&mut self, slot: usize) -> &mut Option<Value> {
+    if let Some(frame) = self.frames.last_mut() {
+      if let Some((_, value)) = frame.locals.iter_mut().find(|(it, _)| *it == slot) {
+        return value;
+      }
+    }
+
+    if slot >= self.vars.len() {
+      self.vars.resize(slot + 1, None);
+    }
+    &mut self.vars[slot]
+  }
+
+  pub fn get_var(&self, slot: usize) -> Option<&Value> {
+    self.scope(slot)?.as_ref()
+  }
+
+  pub fn get_var_mut(&mut self, slot: usize) -> Option<&mut Value> {
+    self.scope_mut(slot).as_mut()
+  }
+
+  pub fn set_var(&mut self, slot: usize, value: Value) {
+    *self.scope_mut(slot) = Some(value);
+  }
+
+  pub fn remove_var(&mut self, slot: usize) {
+    *self.scope_mut(slot) = None;
+  }
+
+  // makes slot local to the innermost call, returning false outside of a function
+  pub fn declare_local(&mut self, slot: usize) -> bool {
+    match self.frames.last_mut() {
+      Some(frame) => {
+        if !frame.locals.iter().any(|(it, _)| *it == slot) {
+          frame.locals.push((slot, None));
+        }
+        true
+      }
+      None => false,
+    }
+  }
+
+  // sets a global by name, for values the program never had to resolve itself
+  pub fn set_global(&mut self, name: &str, value: Value) {
+    let slot = self.slots.resolve(name);
+    if slot >= self.vars.len() {
+      self.vars.resize(slot + 1, None);
+    }
+    self.vars[slot] = Some(value);
   }
 
   pub fn reset_program_state(&mut self) {
     self.vars.clear();
     self.call_stack.clear();
+    self.frames.clear();
+    self.error_handler = None;
+    self.error_line = None;
+    self.data_pointer = 0;
     self.while_stack.clear();
     self.for_stack.clear();
-    self.line_no = 0;
+    self.stack.clear();
+    self.pc = 0;
+    self.stmt_pc = 0;
   }
 
-  pub fn parse_line(&self, tokens: &Vec<Token>, original: String) -> Result<Line, String> {
+  // only plain decimal digits start a numbered line, so an immediate mode line can begin with a
+  // literal like &hFF
+  fn is_line_no(text: &str) -> bool {
+    text.bytes().all(|it| it.is_ascii_digit())
+  }
+
+  pub fn parse_line(&self, tokens: &Tokens, original: String) -> Result<Line, KonekoError> {
     if tokens.is_empty() {
-      return Err("Empty line!".to_string());
+      return Err(tokens.error(0, "Empty line!".to_string()));
     }
 
-    let mut line_no = INVALID_LINE_NO;
-    let begin_idx = match tokens[0] {
-      Token::Integer(num) => {
-        line_no = num as usize;
-        1
-      }
-      _ => 0,
+    let (begin, end) = tokens.span(0);
+    let (line_no, begin_idx) = match tokens[0] {
+      Token::Integer(num) if Self::is_line_no(&original[begin..end]) => (num as usize, 1),
+      _ => (INVALID_LINE_NO, 0),
     };
+    let line = (line_no != INVALID_LINE_NO).then_some(line_no);
+
+    let mut nodes = Vec::<Node>::new();
+    let mut spans = Vec::<(usize, usize)>::new();
+    let mut idx = begin_idx;
+    while idx < tokens.len() {
+      let (ending_idx, node) = self.stmt(idx, tokens).map_err(|error| error.with_line_no(line))?;
+      let span = (tokens.span(idx).0, tokens.span(ending_idx - 1).1);
+      spans.push(span);
+      idx = ending_idx;
+
+      // labels on other lines are checked against once the line is added
+      if let Node::Label(name) = &node {
+        if nodes.contains(&node) {
+          let message = format!("Duplicate label @{}, already defined on the same line", name);
+          return Err(KonekoError::parse(ErrorKind::Syntax, message, span).with_line_no(line));
+        }
+      }
 
-    if begin_idx == tokens.len() {
-      return Ok(Line {
-        line_no,
-        node: Node::Nil,
-        contents: original,
-      });
-    }
-
-    let (ending_idx, node) = self.stmt(begin_idx, tokens)?;
+      // a label already consumed its own ':', and a comment may follow anything
+      let is_label = matches!(node, Node::Label(_));
+      nodes.push(node);
 
-    if ending_idx != tokens.len() {
-      return Err(format!(
-        "Expected end of line, got {:?} on line {}",
-        tokens[ending_idx], line_no
-      ));
+      if idx < tokens.len() && !is_label && !matches!(tokens[idx], Token::Comment(_)) {
+        if tokens[idx] != Token::Colon {
+          let error = tokens.error(idx, format!("Expected end of line, got {:?}", tokens[idx]));
+          return Err(error.with_line_no(line));
+        }
+        idx += 1;
+      }
     }
 
     Ok(Line {
       line_no,
-      node,
+      nodes,
+      spans,
       contents: original,
     })
   }
 
-  pub fn stmt(&self, mut idx: usize, tokens: &Vec<Token>) -> Result<(usize, Node), String> {
+  pub fn stmt(&self, mut idx: usize, tokens: &Tokens) -> Result<(usize, Node), KonekoError> {
     match tokens.get(idx) {
       Some(Token::Identifier(name)) => {
         match name.as_str() {
           "for" => {
             idx += 1;
-            let name = match tokens[idx] {
-              Token::Identifier(ref name) => {
+            let name = match tokens.get(idx) {
+              Some(Token::Identifier(name)) => {
                 let n = name.clone();
                 idx += 1;
                 n
               }
-              _ => return Err(format!("Expected identifier, got {:?}", tokens[idx])),
+              token => return Err(tokens.error(idx, format!("Expected identifier, got {:?}", token))),
             };
 
-            if tokens[idx] != Token::Eq {
-              return Err(format!("Expected '=', got {:?}", tokens[idx]));
+            if tokens.get(idx) != Some(&Token::Eq) {
+              return Err(tokens.error(idx, format!("Expected '=', got {:?}", tokens.get(idx))));
             }
             idx += 1;
 
             let (new_idx, start) = self.expr(idx, tokens)?;
             idx = new_idx;
 
-            if tokens[idx] != Token::To {
-              return Err(format!("Expected 'to', got {:?}", tokens[idx]));
+            if tokens.get(idx) != Some(&Token::To) {
+              return Err(tokens.error(idx, format!("Expected 'to', got {:?}", tokens.get(idx))));
             }
             idx += 1;
 
@@ -401,33 +833,240 @@ impl BASIC {
             let (new_idx, cond) = self.expr(idx, tokens)?;
             idx = new_idx;
 
-            if tokens[idx] != Token::Then {
-              return Err(format!("Expected 'then', got {:?}", tokens[idx]));
+            if tokens.get(idx) != Some(&Token::Then) {
+              return Err(tokens.error(idx, format!("Expected 'then', got {:?}", tokens.get(idx))));
             }
             idx += 1;
 
-            let (new_idx, then) = self.expr(idx, tokens)?;
+            if self.is_stmt_end(idx, tokens) {
+              return Ok((idx, Node::BlockIf { cond: Box::new(cond) }));
+            }
+
+            let (new_idx, then) = self.branch(idx, tokens)?;
             idx = new_idx;
 
             let else_ = match tokens.get(idx) {
               Some(Token::Else) => {
-                idx += 1;
-                let (new_idx, else_) = self.expr(idx, tokens)?;
+                let (new_idx, else_) = self.branch(idx + 1, tokens)?;
                 idx = new_idx;
                 else_
               }
-              _ => Node::Nil,
+              _ => vec![],
             };
 
             return Ok((
               idx,
               Node::If {
                 cond: Box::new(cond),
-                then: Box::new(then),
-                else_: Box::new(else_),
+                then,
+                else_,
+              },
+            ));
+          }
+          "elseif" => {
+            return self.else_if(idx + 1, tokens);
+          }
+          "dim" => {
+            idx += 1;
+            let name = match tokens.get(idx) {
+              Some(Token::Identifier(name)) => name.clone(),
+              token => return Err(tokens.error(idx, format!("Expected array name, got {:?}", token))),
+            };
+            idx += 1;
+
+            let (new_idx, sizes) = self.indices(idx, tokens)?;
+            if sizes.is_empty() {
+              return Err(tokens.error(new_idx, format!("Expected '[', got {:?}", tokens.get(new_idx))));
+            }
+
+            return Ok((
+              new_idx,
+              Node::Assign {
+                name,
+                value: Box::new(Node::EmptyArray(sizes)),
               },
             ));
           }
+          "end" => {
+            if let Some(Token::Identifier(next)) = tokens.get(idx + 1) {
+              if next == "if" {
+                return Ok((idx + 2, Node::EndIf));
+              }
+              if next == "def" {
+                return Ok((idx + 2, Node::EndDef));
+              }
+              if next == "type" {
+                return Ok((idx + 2, Node::EndType));
+              }
+            }
+          }
+          "def" => {
+            idx += 1;
+            let name = match tokens.get(idx) {
+              Some(Token::Identifier(name)) => name.clone(),
+              _ => return Err(tokens.error(idx, format!("Expected function name, got {:?}", tokens.get(idx)))),
+            };
+            idx += 1;
+
+            if self.is_builtin_command(name.as_str()) {
+              return Err(tokens.error(idx - 1, format!("Cannot redefine builtin command {}", name)));
+            }
+
+            if tokens.get(idx) != Some(&Token::LParen) {
+              return Err(tokens.error(idx, format!("Expected '(', got {:?}", tokens.get(idx))));
+            }
+            idx += 1;
+
+            let mut params = Vec::<String>::new();
+            while let Some(Token::Identifier(param)) = tokens.get(idx) {
+              params.push(param.clone());
+              idx += 1;
+              if tokens.get(idx) == Some(&Token::Comma) {
+                idx += 1;
+              }
+            }
+
+            if tokens.get(idx) != Some(&Token::RParen) {
+              return Err(tokens.error(idx, format!("Expected ')', got {:?}", tokens.get(idx))));
+            }
+            idx += 1;
+
+            return Ok((idx, Node::Def { name, params }));
+          }
+          "type" => {
+            return match tokens.get(idx + 1) {
+              Some(Token::Identifier(name)) if !self.is_builtin_command(name) => Ok((idx + 2, Node::Type(name.clone()))),
+              token => Err(tokens.error(idx + 1, format!("Expected type name, got {:?}", token))),
+            };
+          }
+          "return" => {
+            idx += 1;
+            if self.is_stmt_end(idx, tokens) || tokens[idx] == Token::Else {
+              return Ok((idx, Node::Return(Box::new(Node::Nil))));
+            }
+
+            let (new_idx, value) = self.expr(idx, tokens)?;
+            return Ok((new_idx, Node::Return(Box::new(value))));
+          }
+          "on" => {
+            if tokens.get(idx + 1) != Some(&Token::Identifier("error".to_string()))
+              || tokens.get(idx + 2) != Some(&Token::Identifier("gosub".to_string())) {
+              return Err(tokens.error(idx + 1, format!("Expected 'error gosub', got {:?}", tokens.get(idx + 1))));
+            }
+
+            let (new_idx, target) = self.expr(idx + 3, tokens)?;
+            return Ok((new_idx, Node::OnError(Box::new(target))));
+          }
+          "resume" => {
+            if tokens.get(idx + 1) == Some(&Token::Identifier("next".to_string())) {
+              return Ok((idx + 2, Node::Resume(true)));
+            }
+            return Ok((idx + 1, Node::Resume(false)));
+          }
+          "local" => {
+            idx += 1;
+            let mut names = Vec::<String>::new();
+            while let Some(Token::Identifier(name)) = tokens.get(idx) {
+              names.push(name.clone());
+              idx += 1;
+              if tokens.get(idx) == Some(&Token::Comma) {
+                idx += 1;
+              }
+            }
+
+            if names.is_empty() {
+              return Err(tokens.error(idx, format!("Expected identifier, got {:?}", tokens.get(idx))));
+            }
+
+            return Ok((idx, Node::Local(names)));
+          }
+          "data" => {
+            idx += 1;
+            let mut values = Vec::<Value>::new();
+            while !self.is_stmt_end(idx, tokens) {
+              let negative = tokens[idx] == Token::Sub;
+              if negative {
+                idx += 1;
+              }
+
+              let value = match (tokens.get(idx), negative) {
+                (Some(Token::Integer(num)), false) => Value::Integer(*num),
+                (Some(Token::Integer(num)), true) => Value::Integer(
+                  num.checked_neg().ok_or_else(|| {
+                    KonekoError::parse(ErrorKind::Overflow, format!("Integer overflow in -({})", num), tokens.span(idx))
+                  })?
+                ),
+                (Some(Token::Float(num)), _) => Value::Float(if negative { -num } else { *num }),
+                (Some(Token::String(str)), false) => Value::String(str.clone()),
+                (token, _) => return Err(tokens.error(idx, format!("Expected number or string, got {:?}", token))),
+              };
+              values.push(value);
+              idx += 1;
+
+              if tokens.get(idx) == Some(&Token::Comma) {
+                idx += 1;
+              } else if !self.is_stmt_end(idx, tokens) {
+                return Err(tokens.error(idx, format!("Expected ',', got {:?}", tokens.get(idx))));
+              }
+            }
+
+            if values.is_empty() {
+              return Err(tokens.error(idx, format!("Expected number or string, got {:?}", tokens.get(idx))));
+            }
+
+            return Ok((idx, Node::Data(values)));
+          }
+          "read" => {
+            idx += 1;
+            let mut targets = Vec::<Node>::new();
+            loop {
+              let (new_idx, target) = match tokens.get(idx) {
+                Some(Token::Identifier(_)) => self.postfix(idx, tokens)?,
+                token => return Err(tokens.error(idx, format!("Expected identifier, got {:?}", token))),
+              };
+              if !matches!(target, Node::VarGet(_) | Node::IndexGet { .. }) {
+                return Err(tokens.error(idx, format!("Expected variable, got {:?}", target)));
+              }
+              targets.push(target);
+              idx = new_idx;
+
+              if tokens.get(idx) != Some(&Token::Comma) {
+                break;
+              }
+              idx += 1;
+            }
+
+            return Ok((idx, Node::Read(targets)));
+          }
+          "restore" => {
+            idx += 1;
+            if self.is_stmt_end(idx, tokens) || tokens[idx] == Token::Else {
+              return Ok((idx, Node::Restore(Box::new(Node::Nil))));
+            }
+
+            let (new_idx, target) = self.expr(idx, tokens)?;
+            return Ok((new_idx, Node::Restore(Box::new(target))));
+          }
+          "input" => {
+            // the prompt is optional, so the first expression is only known to be one once ';' follows it
+            let mut target_idx = idx + 1;
+            let (mut idx, mut target) = self.expr(target_idx, tokens)?;
+            let mut prompt = Node::String("? ".to_string());
+            if tokens.get(idx) == Some(&Token::Semicolon) {
+              prompt = target;
+              target_idx = idx + 1;
+              (idx, target) = match tokens.get(target_idx) {
+                Some(Token::Identifier(_)) => self.postfix(target_idx, tokens)?,
+                token => return Err(tokens.error(target_idx, format!("Expected identifier, got {:?}", token))),
+              };
+            }
+
+            if !matches!(target, Node::VarGet(_) | Node::IndexGet { .. }) {
+              return Err(tokens.error(target_idx, format!("Expected variable, got {:?}", target)));
+            }
+
+            return Ok((idx, Node::Input { prompt: Box::new(prompt), target: Box::new(target) }));
+          }
           _ => {}
         }
 
@@ -435,7 +1074,7 @@ impl BASIC {
           let mut args = Vec::<Node>::new();
           idx += 1;
 
-          while idx < tokens.len() {
+          while !self.is_stmt_end(idx, tokens) && tokens[idx] != Token::Else {
             let (new_idx, arg) = self.expr(idx, tokens)?;
             args.push(arg);
             idx = new_idx;
@@ -452,25 +1091,69 @@ impl BASIC {
 
         self.expr(idx, tokens)
       }
+      Some(Token::Comment(text)) => {
+        Ok((idx + 1, Node::Comment(text.clone())))
+      }
+      Some(Token::Label(name)) if tokens.get(idx + 1) == Some(&Token::Colon) => {
+        Ok((idx + 2, Node::Label(name.clone())))
+      }
+      Some(Token::Else) => {
+        match tokens.get(idx + 1) {
+          Some(Token::Identifier(name)) if name == "if" => self.else_if(idx + 2, tokens),
+          _ => Ok((idx + 1, Node::Else)),
+        }
+      }
       _ => self.expr(idx, tokens),
     }
   }
 
-  pub fn expr(&self, idx: usize, tokens: &Vec<Token>) -> Result<(usize, Node), String> {
+  fn is_stmt_end(&self, idx: usize, tokens: &Tokens) -> bool {
+    matches!(tokens.get(idx), None | Some(Token::Colon) | Some(Token::Comment(_)))
+  }
+
+  // the statements of a single line if's branch, which like classic BASIC takes the rest of the
+  // line with it, up to an else
+  fn branch(&self, mut idx: usize, tokens: &Tokens) -> Result<(usize, Vec<Node>), KonekoError> {
+    let mut nodes = vec![];
+    loop {
+      let (new_idx, node) = self.stmt(idx, tokens)?;
+      idx = new_idx;
+      nodes.push(node);
+
+      if tokens.get(idx) != Some(&Token::Colon) {
+        return Ok((idx, nodes));
+      }
+      idx += 1;
+    }
+  }
+
+  fn else_if(&self, mut idx: usize, tokens: &Tokens) -> Result<(usize, Node), KonekoError> {
+    let (new_idx, cond) = self.expr(idx, tokens)?;
+    idx = new_idx;
+
+    if tokens.get(idx) != Some(&Token::Then) {
+      return Err(tokens.error(idx, format!("Expected 'then', got {:?}", tokens.get(idx))));
+    }
+    idx += 1;
+
+    Ok((idx, Node::ElseIf { cond: Box::new(cond) }))
+  }
+
+  pub fn expr(&self, idx: usize, tokens: &Tokens) -> Result<(usize, Node), KonekoError> {
     self.or(idx, tokens)
   }
 
-  pub fn or(&self, idx: usize, tokens: &Vec<Token>) -> Result<(usize, Node), String> {
+  pub fn or(&self, idx: usize, tokens: &Tokens) -> Result<(usize, Node), KonekoError> {
     self.bin_op(idx, tokens, Self::and, Self::and, vec![Token::Pipe])
   }
 
-  pub fn and(&self, idx: usize, tokens: &Vec<Token>) -> Result<(usize, Node), String> {
+  pub fn and(&self, idx: usize, tokens: &Tokens) -> Result<(usize, Node), KonekoError> {
     self.bin_op(idx, tokens, Self::cmp, Self::cmp, vec![Token::Ampersand])
   }
 
-  pub fn cmp(&self, mut idx: usize, tokens: &Vec<Token>) -> Result<(usize, Node), String> {
+  pub fn cmp(&self, mut idx: usize, tokens: &Tokens) -> Result<(usize, Node), KonekoError> {
     if idx >= tokens.len() {
-      return Err("Expected expression, got end of line!".to_string());
+      return Err(tokens.error(idx, "Expected expression, got end of line!".to_string()));
     }
 
     match tokens[idx] {
@@ -503,7 +1186,7 @@ impl BASIC {
     }
   }
 
-  pub fn add(&self, idx: usize, tokens: &Vec<Token>) -> Result<(usize, Node), String> {
+  pub fn add(&self, idx: usize, tokens: &Tokens) -> Result<(usize, Node), KonekoError> {
     self.bin_op(
       idx,
       tokens,
@@ -513,9 +1196,9 @@ impl BASIC {
     )
   }
 
-  pub fn mul(&self, mut idx: usize, tokens: &Vec<Token>) -> Result<(usize, Node), String> {
+  pub fn mul(&self, mut idx: usize, tokens: &Tokens) -> Result<(usize, Node), KonekoError> {
     if idx >= tokens.len() {
-      return Err("Expected expression, got end of line!".to_string());
+      return Err(tokens.error(idx, "Expected expression, got end of line!".to_string()));
     }
 
     match tokens[idx] {
@@ -535,15 +1218,120 @@ impl BASIC {
       _ => self.bin_op(
         idx,
         tokens,
-        Self::atom,
-        Self::atom,
+        Self::pow,
+        Self::pow,
         vec![Token::Mul, Token::Div, Token::Percent],
       ),
     }
   }
 
-  pub fn atom(&self, mut idx: usize, tokens: &Vec<Token>) -> Result<(usize, Node), String> {
-    match &tokens[idx] {
+  // right associative, so 2 ^ 3 ^ 2 is 2 ^ 9, and binds tighter than a sign in front of it
+  pub fn pow(&self, idx: usize, tokens: &Tokens) -> Result<(usize, Node), KonekoError> {
+    if idx >= tokens.len() {
+      return Err(tokens.error(idx, "Expected expression, got end of line!".to_string()));
+    }
+
+    let (mut idx, left) = self.postfix(idx, tokens)?;
+    if tokens.get(idx) != Some(&Token::Caret) {
+      return Ok((idx, left));
+    }
+    idx += 1;
+
+    // the exponent may have a sign of its own, as in 2 ^ -1
+    let (idx, right) = match tokens.get(idx) {
+      Some(op @ (Token::Add | Token::Sub)) => {
+        let (idx, right) = self.pow(idx + 1, tokens)?;
+        (idx, Node::UnOp { op: op.clone(), right: Box::new(right) })
+      }
+      _ => self.pow(idx, tokens)?,
+    };
+
+    Ok((
+      idx,
+      Node::BinOp {
+        op: Token::Caret,
+        left: Box::new(left),
+        right: Box::new(right),
+      },
+    ))
+  }
+
+  // any atom can be indexed, as in f(x)[0], {1, 2}[i] or f(x).y
+  pub fn postfix(&self, idx: usize, tokens: &Tokens) -> Result<(usize, Node), KonekoError> {
+    let (idx, node) = self.atom(idx, tokens)?;
+    let (idx, indices) = self.accessors(idx, tokens)?;
+    Ok((idx, Self::indexed(node, indices)))
+  }
+
+  fn indexed(mut node: Node, indices: Vec<Node>) -> Node {
+    for index in indices {
+      node = Node::IndexGet {
+        target: Box::new(node),
+        index: Box::new(index),
+      };
+    }
+    node
+  }
+
+  // [a, b, ... n], giving each expression
+  pub fn bracketed(&self, mut idx: usize, tokens: &Tokens) -> Result<(usize, Vec<Node>), KonekoError> {
+    if tokens.get(idx) != Some(&Token::LSquare) {
+      return Err(tokens.error(idx, format!("Expected '[', got {:?}", tokens.get(idx))));
+    }
+    idx += 1;
+
+    let mut nodes = Vec::<Node>::new();
+    loop {
+      let (new_idx, node) = self.expr(idx, tokens)?;
+      nodes.push(node);
+      idx = new_idx;
+      match tokens.get(idx) {
+        Some(Token::Comma) => idx += 1,
+        Some(Token::RSquare) => return Ok((idx + 1, nodes)),
+        token => return Err(tokens.error(idx, format!("Expected ']', got {:?}", token))),
+      }
+    }
+  }
+
+  // zero or more of [i] or [i, j], so a[i][j] and a[i, j] are the same
+  pub fn indices(&self, mut idx: usize, tokens: &Tokens) -> Result<(usize, Vec<Node>), KonekoError> {
+    let mut indices = Vec::<Node>::new();
+    while tokens.get(idx) == Some(&Token::LSquare) {
+      let (new_idx, nodes) = self.bracketed(idx, tokens)?;
+      indices.extend(nodes);
+      idx = new_idx;
+    }
+    Ok((idx, indices))
+  }
+
+  // indices mixed with .field, which indexes by the field's name
+  pub fn accessors(&self, mut idx: usize, tokens: &Tokens) -> Result<(usize, Vec<Node>), KonekoError> {
+    let mut indices = Vec::<Node>::new();
+    loop {
+      match tokens.get(idx) {
+        Some(Token::LSquare) => {
+          let (new_idx, nodes) = self.bracketed(idx, tokens)?;
+          indices.extend(nodes);
+          idx = new_idx;
+        }
+        Some(Token::Dot) => match tokens.get(idx + 1) {
+          Some(Token::Identifier(field)) => {
+            indices.push(Node::String(field.clone()));
+            idx += 2;
+          }
+          token => return Err(tokens.error(idx + 1, format!("Expected field name, got {:?}", token))),
+        },
+        _ => return Ok((idx, indices)),
+      }
+    }
+  }
+
+  pub fn atom(&self, mut idx: usize, tokens: &Tokens) -> Result<(usize, Node), KonekoError> {
+    let Some(token) = tokens.get(idx) else {
+      return Err(tokens.error(idx, "Expected expression, got end of line!".to_string()));
+    };
+
+    match token {
       Token::Integer(num) => {
         idx += 1;
         Ok((idx, Node::Integer(num.clone())))
@@ -556,6 +1344,10 @@ impl BASIC {
         idx += 1;
         Ok((idx, Node::String(string.clone())))
       }
+      Token::Label(name) => {
+        idx += 1;
+        Ok((idx, Node::LabelRef(name.clone())))
+      }
       Token::Identifier(name) => {
         idx += 1;
         if idx < tokens.len()
@@ -572,6 +1364,9 @@ impl BASIC {
               idx += 1;
             }
           }
+          if idx == tokens.len() {
+            return Err(tokens.error(idx, "Expected ')', got end of line!".to_string()));
+          }
           idx += 1;
           return Ok((
             idx,
@@ -586,14 +1381,33 @@ impl BASIC {
           return Ok((idx, Node::BuiltinCommand { name: name.clone(), args: vec![] }));
         }
 
-        if idx < tokens.len() && tokens[idx] == Token::LSquare {
+        if idx < tokens.len() && tokens[idx] == Token::LParen {
           idx += 1;
-          let (new_idx, index) = self.expr(idx, tokens)?;
-          idx = new_idx;
-          if tokens[idx] != Token::RSquare {
-            return Err(format!("Expected ']', got {:?}", tokens[idx]));
+          let mut args = Vec::<Node>::new();
+          while idx < tokens.len() && tokens[idx] != Token::RParen {
+            let (new_idx, arg) = self.expr(idx, tokens)?;
+            args.push(arg);
+            idx = new_idx;
+            if idx < tokens.len() && tokens[idx] == Token::Comma {
+              idx += 1;
+            }
+          }
+          if idx == tokens.len() {
+            return Err(tokens.error(idx, "Expected ')', got end of line!".to_string()));
           }
           idx += 1;
+          return Ok((
+            idx,
+            Node::Call {
+              name: (*name).clone(),
+              args,
+            },
+          ));
+        }
+
+        if matches!(tokens.get(idx), Some(Token::LSquare | Token::Dot)) {
+          let (new_idx, indices) = self.accessors(idx, tokens)?;
+          idx = new_idx;
           if idx < tokens.len() && tokens[idx] == Token::Eq {
             idx += 1;
             let (new_idx, value) = self.expr(idx, tokens)?;
@@ -602,18 +1416,13 @@ impl BASIC {
               idx,
               Node::IndexSet {
                 name: (*name).clone(),
-                index: Box::new(index),
+                indices,
                 value: Box::new(value),
               },
             ));
           }
-          return Ok((
-            idx,
-            Node::IndexGet {
-              name: (*name).clone(),
-              index: Box::new(index),
-            },
-          ));
+
+          return Ok((idx, Self::indexed(Node::VarGet(name.clone()), indices)));
         }
 
         if idx < tokens.len() && tokens[idx] == Token::Eq {
@@ -632,53 +1441,72 @@ impl BASIC {
         Ok((idx, Node::VarGet(name.clone())))
       }
       Token::LSquare => {
-        // array initialized with nils
-        idx += 1;
-        let (new_idx, size) = self.expr(idx, tokens)?;
-        idx = new_idx;
-        if tokens[idx] != Token::RSquare {
-          return Err(format!("Expected ']', got {:?}", tokens[idx]));
-        }
-        idx += 1;
-        Ok((idx, Node::EmptyArray(Box::new(size))))
+        // array initialized with nils, one level per size
+        let (new_idx, sizes) = self.bracketed(idx, tokens)?;
+        Ok((new_idx, Node::EmptyArray(sizes)))
       }
       Token::LCurly => {
-        // array
+        // array, or map if the first element is followed by ':'
         idx += 1;
+        if tokens.get(idx) == Some(&Token::Colon) {
+          if tokens.get(idx + 1) != Some(&Token::RCurly) {
+            return Err(tokens.error(idx + 1, format!("Expected '}}', got {:?}", tokens.get(idx + 1))));
+          }
+          return Ok((idx + 2, Node::Map(vec![])));
+        }
+
         let mut array = Vec::<Node>::new();
+        let mut entries = Vec::<(Node, Node)>::new();
         while idx < tokens.len() && tokens[idx] != Token::RCurly {
           let (new_idx, node) = self.expr(idx, tokens)?;
           idx = new_idx;
-          array.push(node);
+          if array.is_empty() && tokens.get(idx) == Some(&Token::Colon) {
+            let (new_idx, value) = self.expr(idx + 1, tokens)?;
+            idx = new_idx;
+            entries.push((node, value));
+          } else if !entries.is_empty() {
+            return Err(tokens.error(idx, format!("Expected ':', got {:?}", tokens.get(idx))));
+          } else {
+            array.push(node);
+          }
+
           if idx < tokens.len() && tokens[idx] == Token::Comma {
             idx += 1;
           }
         }
+        if idx == tokens.len() {
+          return Err(tokens.error(idx, "Expected '}', got end of line!".to_string()));
+        }
         idx += 1;
-        Ok((idx, Node::Array(array)))
+
+        if entries.is_empty() {
+          Ok((idx, Node::Array(array)))
+        } else {
+          Ok((idx, Node::Map(entries)))
+        }
       }
       Token::LParen => {
         idx += 1;
         let (new_idx, node) = self.expr(idx, tokens)?;
         idx = new_idx;
-        if tokens[idx] != Token::RParen {
-          return Err(format!("Expected ')', got {:?}", tokens[idx]));
+        if tokens.get(idx) != Some(&Token::RParen) {
+          return Err(tokens.error(idx, format!("Expected ')', got {:?}", tokens.get(idx))));
         }
         idx += 1;
         Ok((idx, node))
       }
-      _ => Err(format!("Expected atom, got {:?}", tokens[idx])),
+      _ => Err(tokens.error(idx, format!("Expected atom, got {:?}", token))),
     }
   }
 
   pub fn bin_op(
     &self,
     mut idx: usize,
-    tokens: &Vec<Token>,
-    lhs: fn(&BASIC, usize, &Vec<Token>) -> Result<(usize, Node), String>,
-    rhs: fn(&BASIC, usize, &Vec<Token>) -> Result<(usize, Node), String>,
+    tokens: &Tokens,
+    lhs: fn(&BASIC, usize, &Tokens) -> Result<(usize, Node), KonekoError>,
+    rhs: fn(&BASIC, usize, &Tokens) -> Result<(usize, Node), KonekoError>,
     ops: Vec<Token>,
-  ) -> Result<(usize, Node), String> {
+  ) -> Result<(usize, Node), KonekoError> {
     let (new_idx, mut left) = lhs(self, idx, tokens)?;
     idx = new_idx;
 
@@ -697,53 +1525,33 @@ impl BASIC {
     Ok((idx, left))
   }
 
-  pub fn lex_line(&self, str: &str) -> (Vec<(Token, usize, usize)>, String) {
-    let str = str.as_bytes();
+  // token spans are byte offsets into src. everything the lexer stops at is ascii, so they
+  // always fall on char boundaries
+  pub fn lex_line(&self, src: &str) -> (Vec<(Token, usize, usize)>, Option<KonekoError>) {
+    let str = src.as_bytes();
     let mut idx = 0;
     let mut tokens = Vec::<(Token, usize, usize)>::new();
 
     while idx < str.len() {
       let begin = idx;
       match str[idx] {
-        b'0'..=b'9' => {
-          let mut num = 0;
-          while idx < str.len() && str[idx] >= b'0' && str[idx] <= b'9' {
-            num = num * 10 + (str[idx] - b'0') as i64;
-            idx += 1;
-          }
-
-          if idx == str.len() {
-            tokens.push((Token::Integer(num), begin, idx));
-            break;
-          }
-
-          if str[idx] == b'.' {
-            idx += 1;
-            let mut dec = 0.0;
-            let mut div = 1.0;
-            while idx < str.len() && str[idx] >= b'0' && str[idx] <= b'9' {
-              dec = dec * 10.0 + (str[idx] - b'0') as f64;
-              div *= 10.0;
-              idx += 1;
+        b'0'..=b'9' | b'.' | b'&' if Self::starts_number(str, idx) => {
+          match Self::lex_number(src, begin) {
+            Ok((token, end)) => {
+              idx = end;
+              tokens.push((token, begin, idx));
             }
-            tokens.push((Token::Float(num as f64 + dec / div), begin, idx));
-          } else {
-            tokens.push((Token::Integer(num), begin, idx));
+            Err(error) => return (tokens, Some(error)),
           }
         }
         b'"' => {
-          idx += 1;
-          let mut string = String::new();
-          while idx < str.len() && str[idx] != b'"' {
-            string.push(str[idx] as char);
-            idx += 1;
-          }
-
-          if idx < str.len() {
-            idx += 1;
+          match Self::lex_string(src, begin) {
+            Ok((string, end)) => {
+              idx = end;
+              tokens.push((Token::String(string), begin, idx));
+            }
+            Err(error) => return (tokens, Some(error)),
           }
-
-          tokens.push((Token::String(string), begin, idx));
         }
         b'<' => {
           idx += 1;
@@ -792,12 +1600,43 @@ impl BASIC {
             idx += 1;
           }
 
+          if var == "rem" {
+            let text = String::from_utf8_lossy(&str[idx..]).trim_start().to_string();
+            tokens.push((Token::Comment(text), begin, str.len()));
+            break;
+          }
+
           if let Some(tok) = self.keywords.get(&var.as_str()) {
             tokens.push(((*tok).clone(), begin, idx));
           } else {
             tokens.push((Token::Identifier(var), begin, idx));
           }
         }
+        b'\'' => {
+          let text = String::from_utf8_lossy(&str[idx + 1..]).trim_start().to_string();
+          tokens.push((Token::Comment(text), begin, str.len()));
+          break;
+        }
+        b'@' => {
+          idx += 1;
+          let mut name = String::new();
+          while idx < str.len()
+            && (str[idx] >= b'a' && str[idx] <= b'z'
+            || str[idx] >= b'A' && str[idx] <= b'Z'
+            || str[idx] == b'_'
+            || str[idx] >= b'0' && str[idx] <= b'9')
+          {
+            name.push(str[idx] as char);
+            idx += 1;
+          }
+
+          if name.is_empty() {
+            let error = format!("Expected label name after '@', at column {}", Self::column(src, begin));
+            return (tokens, Some(KonekoError::lex(ErrorKind::Syntax, error, (begin, idx))));
+          }
+
+          tokens.push((Token::Label(name), begin, idx));
+        }
         b'\t' | b' ' | b'\n' | b'\r' => {
           idx += 1;
         }
@@ -806,12 +1645,142 @@ impl BASIC {
             idx += 1;
             tokens.push(((*tok).clone(), begin, idx));
           } else {
-            return (tokens, format!("Unknown token: {}", str[idx] as char));
+            let char = src[idx..].chars().next().unwrap();
+            let error = format!("Unknown token {}, at column {}", char, Self::column(src, idx));
+            return (tokens, Some(KonekoError::lex(ErrorKind::Syntax, error, (idx, idx + char.len_utf8()))));
           }
         }
       }
     }
 
-    (tokens, "".to_string())
+    (tokens, None)
+  }
+
+  // the column of the char starting at byte idx, counting from 1
+  fn column(src: &str, idx: usize) -> usize {
+    src[..idx].chars().count() + 1
+  }
+
+  // the contents of a string starting at begin, with \", \\, \n, \t and \xNN escapes, and
+  // where it ends. a string missing its closing quote runs to the end of the line
+  fn lex_string(src: &str, begin: usize) -> Result<(String, usize), KonekoError> {
+    let mut string = String::new();
+    let mut chars = src[begin + 1..].char_indices().map(|(idx, char)| (begin + 1 + idx, char));
+    while let Some((idx, char)) = chars.next() {
+      match char {
+        '"' => return Ok((string, idx + 1)),
+        '\\' => {
+          let escape = match chars.next() {
+            Some((_, '"')) => '"',
+            Some((_, '\\')) => '\\',
+            Some((_, 'n')) => '\n',
+            Some((_, 't')) => '\t',
+            Some((_, 'x')) => {
+              // \xNN is the same character as chr(&HNN)
+              let digits = src.get(idx + 2..idx + 4).filter(|it| it.bytes().all(|it| it.is_ascii_hexdigit()));
+              let Some(digits) = digits else {
+                let error = format!("Expected two hex digits after \\x, at column {}", Self::column(src, idx + 2));
+                return Err(KonekoError::lex(ErrorKind::Syntax, error, (idx, idx + 2)));
+              };
+              chars.nth(1);
+              u8::from_str_radix(digits, 16).unwrap() as char
+            }
+            Some((_, other)) => {
+              let error = format!("Unknown escape \\{} in string, at column {}", other, Self::column(src, idx));
+              return Err(KonekoError::lex(ErrorKind::Syntax, error, (idx, idx + 1 + other.len_utf8())));
+            }
+            None => {
+              let error = format!("Expected escape after \\, at column {}", Self::column(src, idx));
+              return Err(KonekoError::lex(ErrorKind::Syntax, error, (idx, idx + 1)));
+            }
+          };
+          string.push(escape);
+        }
+        _ => string.push(char),
+      }
+    }
+
+    Ok((string, src.len()))
+  }
+
+  // a dot is only a number when a digit follows, and &HFF only if every letter after the &H is
+  // a hex digit, so a&hex is still a & hex
+  fn starts_number(str: &[u8], idx: usize) -> bool {
+    match str[idx] {
+      b'0'..=b'9' => true,
+      b'.' => str.get(idx + 1).is_some_and(u8::is_ascii_digit),
+      b'&' if matches!(str.get(idx + 1), Some(b'h' | b'H')) => {
+        let mut digits = str[idx + 2..].iter().take_while(|it| it.is_ascii_alphanumeric()).peekable();
+        digits.peek().is_some() && digits.all(u8::is_ascii_hexdigit)
+      }
+      _ => false,
+    }
+  }
+
+  // 123, 1.5, .5, 1e-3, 0xff, &HFF or 0b1010, giving the token and where it ends
+  fn lex_number(src: &str, begin: usize) -> Result<(Token, usize), KonekoError> {
+    let str = src.as_bytes();
+    let digits = |mut idx: usize, radix: u32| {
+      while idx < str.len() && (str[idx] as char).is_digit(radix) {
+        idx += 1;
+      }
+      idx
+    };
+    let error = |kind: ErrorKind, problem: &str, end: usize| {
+      let message = format!(
+        "Number {} {}, at columns {} to {}",
+        &src[begin..end], problem, Self::column(src, begin), Self::column(src, end) - 1
+      );
+      KonekoError::lex(kind, message, (begin, end))
+    };
+
+    let radix = match str.get(begin..begin + 2) {
+      Some(b"0x" | b"0X" | b"&h" | b"&H") => Some(16),
+      Some(b"0b" | b"0B") => Some(2),
+      _ => None,
+    };
+
+    // hex and binary are bit patterns, so they can fill all 64 bits
+    if let Some(radix) = radix {
+      let end = digits(begin + 2, radix);
+      if end == begin + 2 || str.get(end).is_some_and(u8::is_ascii_alphanumeric) {
+        let end = end + str[end..].iter().take_while(|it| it.is_ascii_alphanumeric()).count();
+        return Err(error(ErrorKind::Syntax, "has invalid digits", end));
+      }
+
+      return match u64::from_str_radix(&src[begin + 2..end], radix) {
+        Ok(num) => Ok((Token::Integer(num as i64), end)),
+        Err(_) => Err(error(ErrorKind::Overflow, "is too large", end)),
+      };
+    }
+
+    let mut idx = digits(begin, 10);
+    let mut float = false;
+    if str.get(idx) == Some(&b'.') {
+      idx = digits(idx + 1, 10);
+      float = true;
+    }
+
+    // an e that isn't followed by digits belongs to whatever comes next
+    if matches!(str.get(idx), Some(b'e' | b'E')) {
+      let sign = matches!(str.get(idx + 1), Some(b'+' | b'-')) as usize;
+      if str.get(idx + 1 + sign).is_some_and(u8::is_ascii_digit) {
+        idx = digits(idx + 1 + sign, 10);
+        float = true;
+      }
+    }
+
+    let text = &src[begin..idx];
+    if float {
+      match text.parse::<f64>() {
+        Ok(num) if num.is_finite() => Ok((Token::Float(num), idx)),
+        _ => Err(error(ErrorKind::Overflow, "is too large", idx)),
+      }
+    } else {
+      match text.parse::<i64>() {
+        Ok(num) => Ok((Token::Integer(num), idx)),
+        Err(_) => Err(error(ErrorKind::Overflow, "is too large", idx)),
+      }
+    }
   }
 }
@@ -11,10 +11,13 @@ use sdl2::video::FullscreenType;
 use crate::koneko::Koneko;
 
 pub mod lex_parse_basic;
+pub mod compile_basic;
+pub mod check_basic;
 pub mod csv;
 pub mod koneko;
 pub mod palette;
 pub mod koneko_basic;
+pub mod koneko_error;
 pub mod koneko_draw;
 
 fn run_koneko(ko: &mut Koneko) {
@@ -58,12 +61,8 @@ fn run_koneko(ko: &mut Koneko) {
         _ => {}
       }
     }
-    let a = ko.execute_code();
-    if let Ok(_val) = a {
-
-    } else if let Err(e) = a {
-      ko.print(format!("Error: {}", e));
-    }
+    // a failed run is already shown in the BASIC screen's error bar
+    let _ = ko.execute_code();
     ko.draw_screen();
 
     unsafe {